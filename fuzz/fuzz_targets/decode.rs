@@ -0,0 +1,18 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+use revsh::message::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(message)) = Message::decode(&mut buf, 1024) {
+        // Whatever decodes has to survive a round trip
+        let mut frame = BytesMut::new();
+        message.encode(&mut frame).unwrap();
+        let decoded = Message::decode(&mut frame, 1024).unwrap().unwrap();
+        assert_eq!(decoded, message);
+        assert!(frame.is_empty());
+    }
+});
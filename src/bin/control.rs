@@ -1,11 +1,15 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::{App, Arg};
 use env_logger::Env;
 use log::{error, info};
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
 
+use revsh::console::Console;
 use revsh::control::Control;
+use revsh::forward::ForwardSpec;
+use revsh::session::SessionTable;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -27,6 +31,48 @@ async fn main() -> Result<()> {
                 .takes_value(true)
                 .help("Dynamic socket forwarding with a local listener"),
         )
+        .arg(
+            Arg::with_name("local_forwarding")
+                .short("L")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Local port forwarding [bind:]port:host:hostport through the target"),
+        )
+        .arg(
+            Arg::with_name("remote_forwarding")
+                .short("R")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Remote port forwarding [bind:]port:host:hostport from the target"),
+        )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .short("t")
+                .takes_value(true)
+                .default_value("10")
+                .help("Seconds to wait for the target to report a proxied connect"),
+        )
+        .arg(
+            Arg::with_name("dial_timeout")
+                .short("T")
+                .takes_value(true)
+                .default_value("10")
+                .help("Seconds to wait when dialing the local end of a remote forward"),
+        )
+        .arg(
+            Arg::with_name("optimistic_connect")
+                .short("O")
+                .help("Grant SOCKS connects right away, for clients that speak first. Closed ports then look open"),
+        )
+        .arg(
+            Arg::with_name("escape_char")
+                .short("e")
+                .takes_value(true)
+                .default_value("~")
+                .help("Escape character for sessions, or none to disable"),
+        )
         .arg(
             Arg::with_name("address")
                 .default_value("0.0.0.0:2200")
@@ -61,6 +107,59 @@ async fn main() -> Result<()> {
 
     info!("Dynamic socket forward: {:?}", proxy_address);
 
+    // Get local forwards
+    let local_forwards = matches
+        .values_of("local_forwarding")
+        .map(|forwards| {
+            forwards
+                .map(str::parse)
+                .collect::<Result<Vec<ForwardSpec>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    for forward in &local_forwards {
+        info!("Local forward: {}", forward);
+    }
+
+    // Get remote forwards
+    let remote_forwards = matches
+        .values_of("remote_forwarding")
+        .map(|forwards| {
+            forwards
+                .map(str::parse)
+                .collect::<Result<Vec<ForwardSpec>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    for forward in &remote_forwards {
+        info!("Remote forward: {}", forward);
+    }
+
+    // Get connect timeout
+    let connect_timeout = Duration::from_secs_f64(
+        matches
+            .value_of("connect_timeout")
+            .expect("No connect timeout")
+            .parse()?,
+    );
+
+    // Get dial timeout
+    let dial_timeout = Duration::from_secs_f64(
+        matches
+            .value_of("dial_timeout")
+            .expect("No dial timeout")
+            .parse()?,
+    );
+
+    // Get escape character
+    let escape_char = match matches.value_of("escape_char").expect("No escape char") {
+        "none" => None,
+        escape_char if escape_char.len() == 1 => Some(escape_char.as_bytes()[0]),
+        escape_char => bail!("Bad escape character: {}", escape_char),
+    };
+
     let listen_address = matches.value_of("address").expect("No listen address");
 
     // Basic environment
@@ -80,27 +179,33 @@ async fn main() -> Result<()> {
     control
         .shell("/bin/bash".to_string())
         .env(env)
-        .proxy(proxy_address);
+        .proxy(proxy_address)
+        .local_forwards(local_forwards)
+        .remote_forwards(remote_forwards)
+        .connect_timeout(connect_timeout)
+        .dial_timeout(dial_timeout)
+        .optimistic_connect(matches.is_present("optimistic_connect"))
+        .escape_char(escape_char);
 
-    // Accept
-    #[allow(unused_mut)]
-    let mut broker = loop {
-        info!("Waiting client...");
-        match control.accept().await {
-            Ok(broker) => break broker,
-            Err(e) => {
-                error!("Accept failed: {}", e);
-                continue;
+    // Accept targets in the background
+    let sessions = SessionTable::new();
+    info!("Waiting for targets...");
+    tokio::spawn({
+        let sessions = sessions.clone();
+        async move {
+            // Sessions already up keep running
+            if let Err(e) = control.serve(sessions).await {
+                error!("{}", e);
             }
         }
-    };
+    });
 
+    // Let the operator pick sessions
+    #[allow(unused_mut)]
+    let mut console = Console::new(sessions);
     #[cfg(feature = "tty")]
-    broker.tty();
-
-    // Run broker
-    info!("Run broker for {}", broker.remote_address);
-    broker.run().await?;
+    console.tty();
+    console.run().await?;
 
     Ok(())
 }
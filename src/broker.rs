@@ -1,312 +1,970 @@
 use anyhow::{bail, Context, Result};
-use log::debug;
+use bytes::{BufMut, BytesMut};
+use log::{debug, error};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-#[allow(unused)]
-use std::sync::{atomic::Ordering, Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, Stderr, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
 use tokio_native_tls::TlsStream;
 
-use crate::control::Control;
-use crate::message::{ConnectionHeaderType, DataType, Message, ProxyHeaderType, ProxyType};
+use crate::connection::{
+    ConnectionKey, ConnectionTable, FlowControl, LocalEnd, LocalItem, ProxyConnection,
+};
+use crate::control::SessionConfig;
+use crate::escape::{Action, EscapeParser};
+use crate::forward::{ForwardCommand, ForwardSpec};
+use crate::frame::Frame;
+use crate::message::ProxyType;
+use crate::protocol::{ControlProtocol, Event, SharedProtocol};
+use crate::reader::MessageReader;
+use crate::socks::{ConnectResult, SocksRequest};
+use crate::transport::Transport;
 #[cfg(feature = "tty")]
-use crate::tty::{Tty, UPDATE_WINSIZE};
+use crate::tty::Tty;
+use crate::version::ProtocolVersion;
+use crate::writer::{MessageWriter, WriterTask};
 
-type TlsReader = Arc<Mutex<Option<ReadHalf<TlsStream<TcpStream>>>>>;
-type TlsWriter = Arc<Mutex<Option<WriteHalf<TlsStream<TcpStream>>>>>;
-type TcpWriter = Arc<Mutex<Option<WriteHalf<TcpStream>>>>;
-type ProxyConnections = Arc<Mutex<HashMap<u16, ProxyConnection>>>;
+type ProxyConnections = Arc<Mutex<ConnectionTable>>;
+type Input = Box<dyn AsyncRead + Send + Unpin>;
+type Output = Box<dyn AsyncWrite + Send + Unpin>;
+type SharedOutput = Arc<Mutex<Output>>;
 
-pub struct Broker {
-    pub remote_address: SocketAddr,
-    reader: TlsReader,
-    writer: TlsWriter,
+// How long a locally closed connection waits for more data from the target
+// before it is dropped
+const CLOSE_LINGER: Duration = Duration::from_secs(30);
+
+/// One session with a target over a `T` stream.
+pub struct Broker<T = TlsStream<TcpStream>> {
+    pub remote_address: String,
+    reader: MessageReader<ReadHalf<T>>,
+    protocol: SharedProtocol,
+    message_data_size: u16,
+    protocol_version: ProtocolVersion,
+    writer: MessageWriter,
+    writer_task: WriterTask<WriteHalf<T>>,
     proxy_address: Option<SocketAddr>,
+    connect_timeout: Duration,
+    dial_timeout: Duration,
+    optimistic_connect: bool,
+    local_forwards: Vec<ForwardSpec>,
+    remote_forwards: Vec<ForwardSpec>,
     proxy_connections: ProxyConnections,
+    input: Option<Input>,
+    output: Option<Output>,
+    escape_char: Option<u8>,
+    winsize: Option<watch::Receiver<(u16, u16)>>,
     #[cfg(feature = "tty")]
     tty: Option<Tty>,
 }
 
-pub struct ProxyConnection {
-    writer: TcpWriter,
-}
-
-impl Broker {
-    pub async fn new(control: &mut Control, remote_address: SocketAddr) -> Result<Self> {
-        let stream = control.stream.lock().await.take().context("error")?;
+impl<T: Transport> Broker<T> {
+    /// Takes over `stream` once `protocol` is established, with the settings
+    /// of `config`.
+    pub fn new(
+        config: &SessionConfig,
+        stream: T,
+        mut protocol: ControlProtocol,
+        remote_address: String,
+    ) -> Self {
+        let message_data_size = protocol.negotiated_message_data_size();
+        let protocol_version = protocol.version().unwrap_or_default();
+        let quirks = protocol.quirks();
+        let buffered = protocol.take_buffered();
+        let protocol = Arc::new(std::sync::Mutex::new(protocol));
         let (r, w) = tokio::io::split(stream);
-        Ok(Self {
+        let (writer, writer_task) = MessageWriter::new(w, message_data_size);
+        let writer_task = writer_task.protocol(protocol.clone());
+        Self {
             remote_address,
-            reader: Arc::new(Mutex::new(Some(r))),
-            writer: Arc::new(Mutex::new(Some(w))),
-            proxy_address: control.proxy_address,
-            proxy_connections: Arc::new(Mutex::new(HashMap::new())),
+            reader: MessageReader::new(r, message_data_size)
+                .quirks(quirks)
+                .buffered(buffered),
+            protocol,
+            message_data_size,
+            protocol_version,
+            writer,
+            writer_task,
+            proxy_address: config.proxy_address,
+            connect_timeout: config.connect_timeout,
+            dial_timeout: config.dial_timeout,
+            optimistic_connect: config.optimistic_connect,
+            local_forwards: config.local_forwards.clone(),
+            remote_forwards: config.remote_forwards.clone(),
+            proxy_connections: Arc::new(Mutex::new(ConnectionTable::new())),
+            input: None,
+            output: None,
+            escape_char: config.escape_char,
+            winsize: None,
             #[cfg(feature = "tty")]
             tty: None,
-        })
+        }
+    }
+
+    /// The payload size negotiated with the target.
+    pub fn message_data_size(&self) -> u16 {
+        self.message_data_size
+    }
+
+    /// The protocol version the target announced.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Reads shell input from `input` instead of stdin.
+    pub fn input<R>(&mut self, input: R) -> &mut Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Writes shell output to `output` instead of stdout.
+    pub fn output<W>(&mut self, output: W) -> &mut Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        self.output = Some(Box::new(output));
+        self
     }
+
+    /// The character starting escape sequences on stdin, `None` turns them
+    /// off.
+    pub fn escape_char(&mut self, escape_char: Option<u8>) -> &mut Self {
+        self.escape_char = escape_char;
+        self
+    }
+
+    /// Sends rows and cols to the target whenever `winsize` changes.
+    pub fn winsize(&mut self, winsize: watch::Receiver<(u16, u16)>) -> &mut Self {
+        self.winsize = Some(winsize);
+        self
+    }
+
+    /// Puts the terminal in raw mode and follows its size.
     #[cfg(feature = "tty")]
-    pub fn tty<'a>(&'a mut self) -> &'a mut Self {
+    pub fn tty(&mut self) -> &mut Self {
         self.tty = Some(Tty::new());
+        match Tty::watch_winsize() {
+            Ok(winsize) => self.winsize = Some(winsize),
+            Err(e) => error!("Can't follow the window size: {}", e),
+        }
         self
     }
 
+    async fn winsize_handler(
+        writer: MessageWriter,
+        mut winsize: watch::Receiver<(u16, u16)>,
+    ) -> Result<()> {
+        while winsize.changed().await.is_ok() {
+            let (rows, cols) = *winsize.borrow_and_update();
+            debug!("Updating winsize {}x{}", rows, cols);
+            writer.send(Frame::Winresize { rows, cols }).await?;
+        }
+        Ok(())
+    }
+
     async fn message_handler(
-        mut reader: TlsReader,
-        #[allow(unused_mut)] mut _writer: TlsWriter,
+        mut reader: MessageReader<ReadHalf<T>>,
+        protocol: SharedProtocol,
+        output: SharedOutput,
+        writer: MessageWriter,
         proxy_connections: ProxyConnections,
+        dial_timeout: Duration,
         #[cfg(feature = "tty")] _tty: Option<Tty>,
     ) -> Result<()> {
-        let mut stdout = tokio::io::stdout();
         let mut stderr = tokio::io::stderr();
         loop {
-            let message = Message::pull(&mut reader).await?;
-            match message.data_type {
-                DataType::Tty => {
-                    stdout.write_all(&message.data).await?;
-                    stdout.flush().await?;
+            loop {
+                let event = match protocol.lock().expect("protocol lock").poll_event() {
+                    Some(event) => event,
+                    None => break,
+                };
+                Self::handle_event(
+                    event,
+                    &writer,
+                    &proxy_connections,
+                    dial_timeout,
+                    &output,
+                    &mut stderr,
+                )
+                .await?;
+            }
+
+            let message = reader.read().await?;
+            let frame = Frame::try_from(message)?;
+            protocol
+                .lock()
+                .expect("protocol lock")
+                .receive_frame(frame)?;
+        }
+    }
+
+    async fn handle_event(
+        event: Event,
+        writer: &MessageWriter,
+        proxy_connections: &ProxyConnections,
+        dial_timeout: Duration,
+        output: &SharedOutput,
+        stderr: &mut Stderr,
+    ) -> Result<()> {
+        match event {
+            Event::Tty(data) => Self::print(output, &data).await?,
+            Event::Error(error) => {
+                stderr.write_all(error.as_bytes()).await?;
+                stderr.write_all(b"\r\n").await?;
+                stderr.flush().await?;
+
+                // Errors carry no connection id. Every pending connect to the
+                // destination the error names is refused, as there is no
+                // telling which one failed, and destroyed on the target in
+                // case it went through there after all.
+                let keys = {
+                    let mut connections = proxy_connections.lock().await;
+                    let keys = connections.pending_named(&error);
+                    for key in &keys {
+                        if let Some(mut proxy_connection) = connections.retire(key) {
+                            proxy_connection.resolve(ConnectResult::Refused);
+                        }
+                    }
+                    keys
+                };
+                for key in keys {
+                    writer.connection_destroy(key).await?;
+                }
+            }
+            Event::RemoteConnect { key, destination } => {
+                let mut proxy_connection = ProxyConnection::new().destination(destination.clone());
+                let local_end = proxy_connection.take_local_end().context("error")?;
+                let key = proxy_connections
+                    .lock()
+                    .await
+                    .insert_remote(key.1, proxy_connection);
+                tokio::spawn(Self::remote_handler(
+                    key,
+                    destination,
+                    local_end,
+                    proxy_connections.clone(),
+                    writer.clone(),
+                    dial_timeout,
+                ));
+            }
+            Event::RemoteClosed(key) => {
+                let mut connections = proxy_connections.lock().await;
+                Self::remote_close(&mut connections, key);
+            }
+            Event::Paused(key) => {
+                if let Some(proxy_connection) = proxy_connections.lock().await.get(&key) {
+                    debug!("Target paused {:?}", key);
+                    proxy_connection.set_paused(true);
                 }
-                DataType::Error => {
-                    stderr.write_all(&message.data).await?;
-                    stderr.write_all(b"\r\n").await?;
-                    stderr.flush().await?;
+            }
+            Event::Resumed(key) => {
+                if let Some(proxy_connection) = proxy_connections.lock().await.get_mut(&key) {
+                    proxy_connection.resolve(ConnectResult::Granted);
+                    proxy_connection.set_paused(false);
                 }
-                DataType::Connection => {
-                    let id = message.header_id;
-                    let mut proxy_connections = proxy_connections.lock().await;
-                    if let Some(proxy_connection) = proxy_connections.get(&id) {
-                        if message.data.is_empty() {
-                            proxy_connections.remove(&id);
-                        } else {
-                            let mut writer = proxy_connection.writer.lock().await;
-                            let writer = writer.as_mut().context("error")?;
-                            writer.write_all(&message.data).await?;
+            }
+            Event::Data { key, data } => {
+                let mut connections = proxy_connections.lock().await;
+                if let Some(proxy_connection) = connections.get_mut(&key) {
+                    proxy_connection.resolve(ConnectResult::Granted);
+                    match proxy_connection.queue_data(data) {
+                        Ok(false) => {}
+                        Ok(true) => {
+                            // The local peer can't keep up
+                            debug!("Send queue full for {:?}", key);
+                            writer.connection_dormant(key).await?;
+                        }
+                        Err(e) => {
+                            debug!("Dropping {:?}: {}", key, e);
+                            connections.retire(&key);
+                            writer.connection_destroy(key).await?;
                         }
                     }
                 }
-                _ => {
-                    debug!("Unknown message: {:?}", message);
-                }
             }
+            Event::Established { .. } => {}
+        }
+        Ok(())
+    }
 
-            #[cfg(feature = "tty")]
-            if UPDATE_WINSIZE.load(Ordering::Relaxed) {
-                debug!("Updating winsize");
-                let tty_winsize = Tty::get_winsize();
-                let mut data = Vec::new();
-                data.extend(u16::to_be_bytes(tty_winsize.ws_row));
-                data.extend(u16::to_be_bytes(tty_winsize.ws_col));
-                Message::new()
-                    .data_type(DataType::Winresize)
-                    .data(data)
-                    .push(&mut _writer)
-                    .await?;
-                UPDATE_WINSIZE.store(false, Ordering::Relaxed);
+    /// Handles a Destroy from the target. A connect still waiting for its
+    /// result is refused, otherwise only the local write half is shut down so
+    /// the local peer can finish sending.
+    fn remote_close(connections: &mut ConnectionTable, key: ConnectionKey) {
+        let proxy_connection = match connections.get_mut(&key) {
+            Some(proxy_connection) => proxy_connection,
+            None => {
+                connections.release_retired(&key);
+                return;
             }
+        };
+        if proxy_connection.pending.is_some() {
+            proxy_connection.resolve(ConnectResult::Refused);
+            connections.remove(&key);
+            return;
+        }
+        debug!("Remote closed {:?}", key);
+        proxy_connection.shutdown();
+        if proxy_connection.is_closed() {
+            connections.remove(&key);
         }
     }
 
-    pub async fn stdin_handler(mut writer: TlsWriter) -> Result<()> {
-        // https://github.com/tokio-rs/tokio/issues/2466
-        let mut stdin = tokio_fd::AsyncFd::try_from(libc::STDIN_FILENO)?;
+    /// Tells the target the local peer is done sending. The entry stays until
+    /// the target closes as well so its remaining data is still delivered, or
+    /// until the target went quiet for `CLOSE_LINGER`.
+    async fn local_close(
+        proxy_connections: ProxyConnections,
+        key: ConnectionKey,
+        remote_writer: MessageWriter,
+    ) -> Result<()> {
+        {
+            let mut connections = proxy_connections.lock().await;
+            let proxy_connection = match connections.get_mut(&key) {
+                Some(proxy_connection) => proxy_connection,
+                None => return Ok(()),
+            };
+            debug!("Local closed {:?}", key);
+            proxy_connection.local_closed = true;
+            if proxy_connection.is_closed() {
+                connections.remove(&key);
+            }
+        }
+        remote_writer.connection_destroy(key).await?;
 
-        let mut buf = [0u8; 1024];
+        // Don't wait forever for a target that never closes its side
+        let mut linger = CLOSE_LINGER;
         loop {
-            let bytes_read = stdin.read(&mut buf).await?;
-            Message::new()
-                .data_type(DataType::Tty)
-                .data(buf[..bytes_read].to_vec())
-                .push(&mut writer)
-                .await?;
+            tokio::time::sleep(linger).await;
+            let mut connections = proxy_connections.lock().await;
+            let idle = match connections.get(&key) {
+                Some(proxy_connection) if proxy_connection.local_closed => {
+                    proxy_connection.last_data.elapsed()
+                }
+                _ => return Ok(()),
+            };
+            if idle >= CLOSE_LINGER {
+                // The id stays taken until the target's Destroy
+                debug!("Dropping idle {:?}", key);
+                connections.retire(&key);
+                return Ok(());
+            }
+            linger = CLOSE_LINGER - idle;
         }
     }
 
-    pub async fn proxy_create(mut writer: TlsWriter, proxy_string: &str) -> Result<()> {
-        Message::new()
-            .data_type(DataType::Proxy)
-            .header_type(ProxyHeaderType::Create)
-            .header_proxy_type(ProxyType::Static)
-            .data(proxy_string.as_bytes().to_vec())
-            .push(&mut writer)
-            .await?;
+    /// Dials the local destination of a target-originated connection. Data
+    /// from the target waits in the send queue until the dial is done.
+    async fn remote_handler(
+        key: ConnectionKey,
+        connection_string: String,
+        local_end: LocalEnd,
+        proxy_connections: ProxyConnections,
+        writer: MessageWriter,
+        dial_timeout: Duration,
+    ) -> Result<()> {
+        debug!("Remote connect to {}", connection_string);
+
+        let stream = match tokio::time::timeout(
+            dial_timeout,
+            TcpStream::connect(&connection_string),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            _ => {
+                debug!("Remote connect to {} failed", connection_string);
+                proxy_connections.lock().await.remove(&key);
+                writer.connection_destroy(key).await?;
+                return Ok(());
+            }
+        };
+
+        Self::spawn_local(stream, local_end, key, proxy_connections, writer, None);
+
         Ok(())
     }
 
-    pub async fn connection_create(
-        mut writer: TlsWriter,
-        id: u16,
-        connection_string: &str,
-    ) -> Result<()> {
-        Message::new()
-            .data_type(DataType::Connection)
-            .header_type(ConnectionHeaderType::Create)
-            .header_id(id)
-            .data(connection_string.as_bytes().to_vec())
-            .push(&mut writer)
-            .await?;
-        Ok(())
+    /// Starts the tasks moving data between a local stream and the target.
+    fn spawn_local(
+        stream: TcpStream,
+        local_end: LocalEnd,
+        key: ConnectionKey,
+        proxy_connections: ProxyConnections,
+        remote_writer: MessageWriter,
+        ready: Option<oneshot::Receiver<()>>,
+    ) {
+        let (r, w) = tokio::io::split(stream);
+
+        tokio::spawn(Self::local_writer(
+            w,
+            local_end.queue,
+            local_end.flow,
+            key,
+            proxy_connections.clone(),
+            remote_writer.clone(),
+        ));
+
+        tokio::spawn(Self::proxy_reader(
+            r,
+            key,
+            proxy_connections,
+            remote_writer,
+            local_end.paused,
+            ready,
+        ));
     }
 
-    pub async fn connection_data(mut writer: TlsWriter, id: u16, data: &[u8]) -> Result<()> {
-        Message::new()
-            .data_type(DataType::Connection)
-            .header_type(ConnectionHeaderType::Data)
-            .header_id(id)
-            .data(data.to_vec())
-            .push(&mut writer)
-            .await?;
+    /// Drains the send queue of a connection into the local peer and lets the
+    /// target resume once a slow peer caught up.
+    async fn local_writer(
+        mut local_writer: WriteHalf<TcpStream>,
+        mut queue: mpsc::UnboundedReceiver<LocalItem>,
+        flow: Arc<FlowControl>,
+        key: ConnectionKey,
+        proxy_connections: ProxyConnections,
+        remote_writer: MessageWriter,
+    ) -> Result<()> {
+        while let Some(item) = queue.recv().await {
+            match item {
+                LocalItem::Data(data) => {
+                    if local_writer.write_all(&data).await.is_err() {
+                        // The local peer is gone, drop it on both sides
+                        debug!("Local write failed for {:?}", key);
+                        if proxy_connections.lock().await.retire(&key).is_some() {
+                            remote_writer.connection_destroy(key).await?;
+                        }
+                        return Ok(());
+                    }
+                    if flow.drained(data.len()) {
+                        debug!("Send queue drained for {:?}", key);
+                        remote_writer.connection_active(key).await?;
+                    }
+                }
+                LocalItem::Shutdown => {
+                    let _ = local_writer.shutdown().await;
+                }
+            }
+        }
         Ok(())
     }
 
-    pub async fn proxy_handler(
-        mut stream: TcpStream,
-        proxy_connections: ProxyConnections,
-        id: u16,
-        writer: TlsWriter,
+    /// Sends keyboard input to the target and handles the escape sequences
+    /// in it. Returns once the input is closed or on `~.`.
+    async fn stdin_handler(
+        writer: MessageWriter,
+        mut input: Input,
+        output: SharedOutput,
+        mut forwards: Forwards,
+        escape_char: Option<u8>,
     ) -> Result<()> {
-        let mut buf = [0u8; 1];
+        let size = usize::from(writer.message_data_size());
+        let mut buf = BytesMut::with_capacity(size);
+        let mut escapes = escape_char.map(EscapeParser::new);
+        loop {
+            buf.reserve(size);
+            if input.read_buf(&mut (&mut buf).limit(size)).await? == 0 {
+                return Ok(());
+            }
+            let data = buf.split().freeze();
+            let escapes = match &mut escapes {
+                Some(escapes) => escapes,
+                None => {
+                    writer.send(Frame::Tty(data)).await?;
+                    continue;
+                }
+            };
+            for action in escapes.feed(&data) {
+                match action {
+                    Action::Send(data) => writer.send(Frame::Tty(data)).await?,
+                    Action::Echo(data) => Self::print(&output, &data).await?,
+                    Action::Disconnect => {
+                        Self::print(&output, b"\r\nDisconnecting\r\n").await?;
+                        return Ok(());
+                    }
+                    Action::Help => Self::print(&output, escapes.help().as_bytes()).await?,
+                    Action::ListConnections => {
+                        let list = Self::format_connections(&forwards.proxy_connections).await;
+                        Self::print(&output, list.as_bytes()).await?;
+                    }
+                    Action::Command(line) => {
+                        let reply = match Self::command(&mut forwards, &line).await {
+                            Ok(reply) => reply,
+                            Err(e) => format!("{}\r\n", e),
+                        };
+                        Self::print(&output, reply.as_bytes()).await?;
+                    }
+                }
+            }
+        }
+    }
 
-        stream.read_exact(&mut buf).await?;
-        let version = u8::from_be_bytes(buf);
-        if version != 4 {
-            bail!("Wrong socks version");
+    /// Runs a line from the `~C` command line and returns what to show.
+    async fn command(forwards: &mut Forwards, line: &str) -> Result<String> {
+        let reply = match line.parse()? {
+            ForwardCommand::Help => return Ok(ForwardCommand::HELP.to_string()),
+            ForwardCommand::Dynamic(listen_address) => {
+                Self::add_dynamic(forwards, listen_address.clone()).await?;
+                format!("Forwarding {} dynamically", listen_address)
+            }
+            ForwardCommand::Local(forward) => {
+                Self::add_local(forwards, forward.clone()).await?;
+                format!("Forwarding {} locally", forward)
+            }
+            ForwardCommand::Remote(forward) => {
+                Self::add_remote(forwards, forward.clone()).await?;
+                format!("Forwarding {} remotely", forward)
+            }
+            ForwardCommand::CancelDynamic(listen_address) => {
+                forwards
+                    .dynamic
+                    .remove(&listen_address)
+                    .with_context(|| format!("No dynamic forward on {}", listen_address))?
+                    .abort();
+                format!("Cancelled dynamic forward on {}", listen_address)
+            }
+            ForwardCommand::CancelLocal(listen_address) => {
+                forwards
+                    .local
+                    .remove(&listen_address)
+                    .with_context(|| format!("No local forward on {}", listen_address))?
+                    .abort();
+                format!("Cancelled local forward on {}", listen_address)
+            }
+            ForwardCommand::CancelRemote(listen_address) => {
+                let id = forwards
+                    .remote
+                    .remove(&listen_address)
+                    .with_context(|| format!("No remote forward on {}", listen_address))?;
+                forwards.writer.proxy_destroy(id).await?;
+                format!("Cancelled remote forward on {}", listen_address)
+            }
+        };
+        Ok(reply + "\r\n")
+    }
+
+    /// Starts a SOCKS listener on `listen_address`.
+    async fn add_dynamic(forwards: &mut Forwards, listen_address: String) -> Result<()> {
+        if forwards.dynamic.contains_key(&listen_address) {
+            bail!("Already forwarding {}", listen_address);
         }
+        let listener = TcpListener::bind(&listen_address).await?;
+        let task = tokio::spawn(Self::proxy_listener(
+            listener,
+            forwards.proxy_connections.clone(),
+            forwards.writer.clone(),
+            forwards.connect_timeout,
+            forwards.optimistic_connect,
+        ));
+        forwards.dynamic.insert(listen_address, task);
+        Ok(())
+    }
 
-        stream.read_exact(&mut buf).await?;
-        let command = u8::from_be_bytes(buf);
-        if command != 1 {
-            bail!("Wrong command");
+    /// Starts a listener for a static forward through the target.
+    async fn add_local(forwards: &mut Forwards, forward: ForwardSpec) -> Result<()> {
+        let listen_address = forward.listen_address();
+        if forwards.local.contains_key(&listen_address) {
+            bail!("Already forwarding {}", listen_address);
         }
+        let listener = TcpListener::bind(&listen_address).await?;
+        let task = tokio::spawn(Self::static_listener(
+            listener,
+            forward,
+            forwards.proxy_connections.clone(),
+            forwards.writer.clone(),
+        ));
+        forwards.local.insert(listen_address, task);
+        Ok(())
+    }
 
-        let mut buf = [0u8; 2];
-        stream.read_exact(&mut buf).await?;
-        let dst_port = u16::from_be_bytes(buf);
+    /// Asks the target to listen for a forward back to this side.
+    async fn add_remote(forwards: &mut Forwards, forward: ForwardSpec) -> Result<()> {
+        let listen_address = forward.listen_address();
+        if forwards.remote.contains_key(&listen_address) {
+            bail!("Already forwarding {}", listen_address);
+        }
+        debug!("Remote forward {}", forward);
+        let id = forwards.proxy_id();
+        forwards
+            .writer
+            .proxy_create(id, &forward.to_string())
+            .await?;
+        forwards.remote.insert(listen_address, id);
+        Ok(())
+    }
 
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf).await?;
-        let dst_ip = std::net::Ipv4Addr::from(buf);
+    async fn format_connections(proxy_connections: &ProxyConnections) -> String {
+        let proxy_connections = proxy_connections.lock().await;
+        let mut connections: Vec<_> = proxy_connections.iter().collect();
+        connections.sort_by_key(|((origin, id), _)| (*origin as u16, *id));
 
-        let mut buf = [0u8; 1];
-        stream.read_exact(&mut buf).await?;
+        let mut list = "The following connections are open:\r\n".to_string();
+        for ((origin, id), proxy_connection) in connections {
+            let state = if proxy_connection.pending.is_some() {
+                "connecting"
+            } else if proxy_connection.local_closed {
+                "local closed"
+            } else if proxy_connection.remote_closed {
+                "remote closed"
+            } else {
+                "open"
+            };
+            list.push_str(&format!(
+                "  #{} {:?} {} ({})\r\n",
+                id, origin, proxy_connection.destination, state
+            ));
+        }
+        list
+    }
 
-        let connection_string = format!("{}:{}", dst_ip, dst_port);
+    /// Shows `data` on the local terminal.
+    async fn print(output: &SharedOutput, data: &[u8]) -> Result<()> {
+        let mut output = output.lock().await;
+        output.write_all(data).await?;
+        output.flush().await?;
+        Ok(())
+    }
 
-        Self::connection_create(writer.clone(), id, &connection_string).await?;
+    pub async fn proxy_handler(
+        mut stream: TcpStream,
+        proxy_connections: ProxyConnections,
+        writer: MessageWriter,
+        connect_timeout: Duration,
+        optimistic_connect: bool,
+    ) -> Result<()> {
+        let request = SocksRequest::read(&mut stream).await?;
+        let connection_string = request.connection_string();
+        debug!(
+            "Socks {:?} connect to {}",
+            request.version, connection_string
+        );
 
-        stream.write_all(&u8::to_be_bytes(0)).await?;
-        stream.write_all(&u8::to_be_bytes(90)).await?;
-        stream.write_all(&u16::to_be_bytes(dst_port)).await?;
-        stream.write_all(&dst_ip.octets()).await?;
+        let (ready, ready_receiver) = oneshot::channel();
+        let mut proxy_connection = ProxyConnection::new()
+            .destination(connection_string.clone())
+            .pending(request, ready);
+        let local_end = proxy_connection.take_local_end().context("error")?;
 
-        let (r, w) = tokio::io::split(stream);
+        let key = proxy_connections
+            .lock()
+            .await
+            .insert_local(proxy_connection)
+            .context("Out of connection ids")?;
 
-        {
-            let mut proxy_connections = proxy_connections.lock().await;
-            proxy_connections.insert(
-                id,
-                ProxyConnection {
-                    writer: Arc::new(Mutex::new(Some(w))),
-                },
-            );
+        writer
+            .connection_create(key.1, ProxyType::Dynamic, &connection_string)
+            .await?;
+
+        if optimistic_connect {
+            if let Some(proxy_connection) = proxy_connections.lock().await.get_mut(&key) {
+                proxy_connection.resolve(ConnectResult::Granted);
+            }
+        } else {
+            tokio::spawn(Self::connect_timeout(
+                key,
+                proxy_connections.clone(),
+                writer.clone(),
+                connect_timeout,
+            ));
         }
 
-        tokio::spawn(Self::proxy_reader(
-            r,
-            id,
-            proxy_connections.clone(),
-            writer.clone(),
-        ));
+        Self::spawn_local(
+            stream,
+            local_end,
+            key,
+            proxy_connections,
+            writer,
+            Some(ready_receiver),
+        );
 
         Ok(())
     }
 
+    /// Fails a connect the target didn't report on in time. Data from the
+    /// target grants a connect, so a client that has to speak first only
+    /// gets through with `optimistic_connect`.
+    async fn connect_timeout(
+        key: ConnectionKey,
+        proxy_connections: ProxyConnections,
+        writer: MessageWriter,
+        connect_timeout: Duration,
+    ) -> Result<()> {
+        tokio::time::sleep(connect_timeout).await;
+        let mut connections = proxy_connections.lock().await;
+        match connections.get(&key) {
+            Some(proxy_connection) if proxy_connection.pending.is_some() => {}
+            _ => return Ok(()),
+        }
+        debug!("Connect timed out for {:?}", key);
+        if let Some(mut proxy_connection) = connections.retire(&key) {
+            proxy_connection.resolve(ConnectResult::TimedOut);
+        }
+        drop(connections);
+        writer.connection_destroy(key).await
+    }
+
     pub async fn proxy_reader(
         mut local_reader: ReadHalf<TcpStream>,
-        id: u16,
+        key: ConnectionKey,
         proxy_connections: ProxyConnections,
-        remote_writer: TlsWriter,
+        remote_writer: MessageWriter,
+        mut paused: watch::Receiver<bool>,
+        ready: Option<oneshot::Receiver<()>>,
     ) -> Result<()> {
+        // Don't forward anything before the target granted the connect
+        if let Some(ready) = ready {
+            if ready.await.is_err() {
+                return Ok(());
+            }
+        }
+        let size = usize::from(remote_writer.message_data_size());
+        let mut buf = BytesMut::with_capacity(size);
         loop {
-            let mut buf = [0u8; 1024];
+            // Stop reading while the target is marked Dormant
+            if *paused.borrow_and_update() {
+                if paused.changed().await.is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+            buf.reserve(size);
+            let mut chunk = (&mut buf).limit(size);
             tokio::select! {
-                n = local_reader.read(&mut buf) => {
+                n = local_reader.read_buf(&mut chunk) => {
                     match n {
                         Ok(n) => {
                             if n < 1 {
                                 break;
                             }
-                            Self::connection_data(remote_writer.clone(), id, &buf[..n]).await?;
+                            if proxy_connections.lock().await.get(&key).is_none() {
+                                return Ok(());
+                            }
+                            // A Destroy from the target only means it is done
+                            // sending, it may still read
+                            remote_writer.connection_data(key, buf.split().freeze())
+                                .await?;
                         }
                         _ => break,
                     }
                 },
+                // A Dormant also stops a read that is already waiting
+                changed = paused.changed() => {
+                    if changed.is_err() {
+                        return Ok(());
+                    }
+                },
                 _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {
                     let proxy_connections = proxy_connections.lock().await;
-                    if proxy_connections.get(&id).is_none() {
-                        break;
+                    if proxy_connections.get(&key).is_none() {
+                        return Ok(());
                     }
                 },
             }
         }
-        let mut proxy_connections = proxy_connections.lock().await;
-        proxy_connections.remove(&id);
-        Ok(())
+        Self::local_close(proxy_connections, key, remote_writer).await
     }
 
     pub async fn proxy_listener(
-        listen_address: String,
+        listener: TcpListener,
         proxy_connections: ProxyConnections,
-        writer: TlsWriter,
+        writer: MessageWriter,
+        connect_timeout: Duration,
+        optimistic_connect: bool,
     ) -> Result<()> {
-        let listener = TcpListener::bind(listen_address).await?;
-
         loop {
-            if let Ok((stream, address)) = listener.accept().await {
+            if let Ok((stream, _)) = listener.accept().await {
                 tokio::spawn(Self::proxy_handler(
                     stream,
                     proxy_connections.clone(),
-                    address.port(),
                     writer.clone(),
+                    connect_timeout,
+                    optimistic_connect,
                 ));
             }
         }
     }
 
-    pub async fn run(self) -> Result<()> {
-        if let Some(proxy_address) = self.proxy_address {
-            Self::proxy_create(
-                self.writer.clone(),
-                &format!("{}:127.0.0.1:1081", proxy_address.port()),
-            )
+    pub async fn static_handler(
+        stream: TcpStream,
+        proxy_connections: ProxyConnections,
+        writer: MessageWriter,
+        connection_string: String,
+    ) -> Result<()> {
+        debug!("Static connect to {}", connection_string);
+
+        let mut proxy_connection = ProxyConnection::new().destination(connection_string.clone());
+        let local_end = proxy_connection.take_local_end().context("error")?;
+
+        let key = proxy_connections
+            .lock()
+            .await
+            .insert_local(proxy_connection)
+            .context("Out of connection ids")?;
+
+        writer
+            .connection_create(key.1, ProxyType::Static, &connection_string)
             .await?;
+
+        Self::spawn_local(stream, local_end, key, proxy_connections, writer, None);
+
+        Ok(())
+    }
+
+    pub async fn static_listener(
+        listener: TcpListener,
+        forward: ForwardSpec,
+        proxy_connections: ProxyConnections,
+        writer: MessageWriter,
+    ) -> Result<()> {
+        loop {
+            if let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(Self::static_handler(
+                    stream,
+                    proxy_connections.clone(),
+                    writer.clone(),
+                    forward.connection_string(),
+                ));
+            }
         }
-        let message_handler = tokio::spawn(Self::message_handler(
-            self.reader.clone(),
+    }
+
+    pub async fn run(self) -> Result<()> {
+        // Nothing may fail once the tasks below run, they'd be left behind
+        let input = match self.input {
+            Some(input) => input,
+            // https://github.com/tokio-rs/tokio/issues/2466
+            None => Box::new(tokio_fd::AsyncFd::try_from(libc::STDIN_FILENO)?),
+        };
+        let mut writer_task = tokio::spawn(self.writer_task.run());
+        let output: SharedOutput = Arc::new(Mutex::new(
+            self.output.unwrap_or_else(|| Box::new(tokio::io::stdout())),
+        ));
+        let mut message_handler = tokio::spawn(Self::message_handler(
+            self.reader,
+            self.protocol,
+            output.clone(),
             self.writer.clone(),
             self.proxy_connections.clone(),
+            self.dial_timeout,
             #[cfg(feature = "tty")]
             self.tty,
         ));
+
+        // A forward that fails, e.g. on a port another session holds, doesn't end
+        // the session
+        let mut forwards = Forwards::new(
+            self.writer.clone(),
+            self.proxy_connections,
+            self.connect_timeout,
+            self.optimistic_connect,
+        );
         if let Some(proxy_address) = self.proxy_address {
-            tokio::spawn(Self::proxy_listener(
-                format!("{}:{}", proxy_address.ip(), proxy_address.port()),
-                self.proxy_connections.clone(),
-                self.writer.clone(),
-            ));
+            if let Err(e) = Self::add_dynamic(&mut forwards, proxy_address.to_string()).await {
+                error!("Dynamic forward on {} failed: {}", proxy_address, e);
+            }
+        }
+        for forward in self.local_forwards {
+            let listen_address = forward.listen_address();
+            if let Err(e) = Self::add_local(&mut forwards, forward).await {
+                error!("Local forward on {} failed: {}", listen_address, e);
+            }
+        }
+        for forward in self.remote_forwards {
+            let listen_address = forward.listen_address();
+            if let Err(e) = Self::add_remote(&mut forwards, forward).await {
+                error!("Remote forward on {} failed: {}", listen_address, e);
+            }
         }
-        let stdin_handler = tokio::spawn(Self::stdin_handler(self.writer));
+        let winsize_handler = self
+            .winsize
+            .map(|winsize| tokio::spawn(Self::winsize_handler(self.writer.clone(), winsize)));
+        let mut stdin_handler = tokio::spawn(Self::stdin_handler(
+            self.writer,
+            input,
+            output,
+            forwards,
+            self.escape_char,
+        ));
 
         tokio::select! {
-            _ = stdin_handler => {
+            _ = &mut writer_task => {
+                debug!("writer_task() exited");
+            }
+            _ = &mut stdin_handler => {
                 debug!("stdin_handler() exited");
             }
-            _ = message_handler => {
+            _ = &mut message_handler => {
                 debug!("message_handler() exited");
             }
         };
 
+        // Frees the listen ports and the output for whoever runs next
+        writer_task.abort();
+        stdin_handler.abort();
+        message_handler.abort();
+        if let Some(winsize_handler) = winsize_handler {
+            winsize_handler.abort();
+        }
+
         Ok(())
     }
 }
+
+/// The forwards of a session, from the command line or added later with
+/// `~C`. Dropping it stops the local listeners.
+struct Forwards {
+    writer: MessageWriter,
+    proxy_connections: ProxyConnections,
+    connect_timeout: Duration,
+    optimistic_connect: bool,
+    /// SOCKS listeners by listen address
+    dynamic: HashMap<String, JoinHandle<Result<()>>>,
+    /// Static forward listeners by listen address
+    local: HashMap<String, JoinHandle<Result<()>>>,
+    /// Proxy ids on the target by listen address
+    remote: HashMap<String, u16>,
+    next_proxy_id: u16,
+}
+
+impl Forwards {
+    fn new(
+        writer: MessageWriter,
+        proxy_connections: ProxyConnections,
+        connect_timeout: Duration,
+        optimistic_connect: bool,
+    ) -> Self {
+        Self {
+            writer,
+            proxy_connections,
+            connect_timeout,
+            optimistic_connect,
+            dynamic: HashMap::new(),
+            local: HashMap::new(),
+            remote: HashMap::new(),
+            next_proxy_id: 0,
+        }
+    }
+
+    fn proxy_id(&mut self) -> u16 {
+        let id = self.next_proxy_id;
+        self.next_proxy_id = self.next_proxy_id.wrapping_add(1);
+        id
+    }
+}
+
+impl Drop for Forwards {
+    fn drop(&mut self) {
+        for listener in self.dynamic.values() {
+            listener.abort();
+        }
+        for listener in self.local.values() {
+            listener.abort();
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::frame::Frame;
+use crate::message::{Message, ProtocolError};
+use crate::version::Quirks;
+
+/// The revsh wire format as a codec, so any `AsyncRead + AsyncWrite` can be
+/// wrapped in `Framed` and read or written as a stream of messages.
+#[derive(Debug, Clone, Copy)]
+pub struct RevshCodec {
+    max_data_size: u16,
+    quirks: Quirks,
+}
+
+impl RevshCodec {
+    pub fn new() -> Self {
+        Self {
+            max_data_size: u16::MAX,
+            quirks: Quirks::default(),
+        }
+    }
+
+    /// Limits payloads to the message data size negotiated with the peer.
+    pub fn max_data_size(mut self, max_data_size: u16) -> Self {
+        self.max_data_size = max_data_size;
+        self
+    }
+
+    /// Frames messages the way the negotiated protocol version does.
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+}
+
+impl Default for RevshCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for RevshCodec {
+    type Item = Message;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, ProtocolError> {
+        Message::decode_with(src, self.max_data_size, self.quirks)
+    }
+}
+
+impl Encoder<Message> for RevshCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        self.encode(&message, dst)
+    }
+}
+
+impl Encoder<&Message> for RevshCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        if message.data.len() > usize::from(self.max_data_size) {
+            return Err(ProtocolError::DataLen {
+                data_len: message.data.len(),
+                max_data_size: self.max_data_size,
+            });
+        }
+        dst.reserve(message.encoded_len_with(self.quirks));
+        message.encode_with(dst, self.quirks)
+    }
+}
+
+impl Encoder<Frame> for RevshCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        self.encode(Message::from(frame), dst)
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use log::debug;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::message::HeaderOrigin;
+use crate::socks::{ConnectResult, SocksRequest};
+
+// Bytes queued for a slow local peer before the target is told to pause
+pub const QUEUE_HIGH_WATERMARK: usize = 256 * 1024;
+// Bytes left in the queue when the target is told to resume
+pub const QUEUE_LOW_WATERMARK: usize = 64 * 1024;
+// Hard bound for a target that keeps sending after Dormant
+pub const QUEUE_LIMIT: usize = 4 * 1024 * 1024;
+
+/// Connections are identified by the side that created them and the id that
+/// side picked, so control and target ids never clash.
+pub type ConnectionKey = (HeaderOrigin, u16);
+
+/// Work for the task writing to the local peer.
+#[derive(Debug)]
+pub enum LocalItem {
+    Data(Bytes),
+    Shutdown,
+}
+
+/// Send queue accounting shared with the local writer task.
+///
+/// The send queue itself is an unbounded channel, it is bounded by counting
+/// the bytes in it instead: the target is told to pause at
+/// `QUEUE_HIGH_WATERMARK` and a connection whose target keeps sending is
+/// dropped past `QUEUE_LIMIT`.
+#[derive(Default)]
+pub struct FlowControl {
+    queued: AtomicUsize,
+    dormant: AtomicBool,
+}
+
+impl FlowControl {
+    /// Accounts for data written to the local peer. Returns true when the
+    /// queue drained far enough that the target may resume sending.
+    pub fn drained(&self, len: usize) -> bool {
+        let queued = self.queued.fetch_sub(len, Ordering::SeqCst) - len;
+        queued <= QUEUE_LOW_WATERMARK && self.dormant.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// The local side of a connection, owned by its reader and writer tasks.
+pub struct LocalEnd {
+    pub queue: mpsc::UnboundedReceiver<LocalItem>,
+    pub flow: Arc<FlowControl>,
+    pub paused: watch::Receiver<bool>,
+}
+
+/// Each side sends a Destroy once it is done sending. A connection is gone
+/// when both sides have closed, which keeps half-closed streams working.
+pub struct ProxyConnection {
+    queue: mpsc::UnboundedSender<LocalItem>,
+    queue_receiver: Option<mpsc::UnboundedReceiver<LocalItem>>,
+    flow: Arc<FlowControl>,
+    paused: watch::Sender<bool>,
+    /// Where the connection goes, for listing
+    pub destination: String,
+    pub pending: Option<PendingConnect>,
+    pub local_closed: bool,
+    pub remote_closed: bool,
+    /// When data for the local peer was last queued
+    pub last_data: Instant,
+}
+
+/// A SOCKS request waiting for the target to report the connect result.
+pub struct PendingConnect {
+    pub request: SocksRequest,
+    pub ready: oneshot::Sender<()>,
+}
+
+impl ProxyConnection {
+    pub fn new() -> Self {
+        let (queue, queue_receiver) = mpsc::unbounded_channel();
+        let (paused, _) = watch::channel(false);
+        Self {
+            queue,
+            queue_receiver: Some(queue_receiver),
+            flow: Arc::new(FlowControl::default()),
+            paused,
+            destination: String::new(),
+            pending: None,
+            local_closed: false,
+            remote_closed: false,
+            last_data: Instant::now(),
+        }
+    }
+
+    pub fn destination(mut self, destination: String) -> Self {
+        self.destination = destination;
+        self
+    }
+
+    pub fn pending(mut self, request: SocksRequest, ready: oneshot::Sender<()>) -> Self {
+        self.pending = Some(PendingConnect { request, ready });
+        self
+    }
+
+    /// Hands the send queue and the pause flag over to the local tasks.
+    pub fn take_local_end(&mut self) -> Option<LocalEnd> {
+        Some(LocalEnd {
+            queue: self.queue_receiver.take()?,
+            flow: self.flow.clone(),
+            paused: self.paused.subscribe(),
+        })
+    }
+
+    /// Pauses or resumes reading from the local peer, as asked by the target.
+    pub fn set_paused(&self, paused: bool) {
+        let _ = self.paused.send(paused);
+    }
+
+    /// Queues data for the local peer. Returns true when the queue just went
+    /// over the high watermark and the target should be told to pause.
+    pub fn queue_data(&mut self, data: Bytes) -> Result<bool> {
+        self.last_data = Instant::now();
+        let len = data.len();
+        let queued = self.flow.queued.fetch_add(len, Ordering::SeqCst) + len;
+        if queued > QUEUE_LIMIT {
+            bail!("Send queue overflow");
+        }
+        self.queue
+            .send(LocalItem::Data(data))
+            .map_err(|_| anyhow!("Local writer gone"))?;
+        Ok(queued >= QUEUE_HIGH_WATERMARK && !self.flow.dormant.swap(true, Ordering::SeqCst))
+    }
+
+    /// Sends the SOCKS reply of a pending connect, if any, and lets the local
+    /// reader start once the connect was granted.
+    pub fn resolve(&mut self, result: ConnectResult) {
+        if let Some(pending) = self.pending.take() {
+            debug!(
+                "Connect to {} {:?}",
+                pending.request.connection_string(),
+                result
+            );
+            let _ = self.queue_data(pending.request.reply(result).into());
+            if result == ConnectResult::Granted {
+                let _ = pending.ready.send(());
+            }
+        }
+    }
+
+    /// Shuts down the local write half once the target is done sending.
+    pub fn shutdown(&mut self) {
+        self.remote_closed = true;
+        let _ = self.queue.send(LocalItem::Shutdown);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.local_closed && self.remote_closed
+    }
+}
+
+impl Default for ProxyConnection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hands out control side connection ids. Every id is used once before any
+/// is reused, then freed ids come back oldest first, so a late message for a
+/// closed connection is unlikely to hit a new one.
+#[derive(Default)]
+pub struct IdAllocator {
+    next: u32,
+    free: VecDeque<u16>,
+    used: HashSet<u16>,
+}
+
+impl IdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allocate(&mut self) -> Option<u16> {
+        let id = if self.next <= u16::MAX.into() {
+            self.next += 1;
+            (self.next - 1) as u16
+        } else {
+            self.free.pop_front()?
+        };
+        self.used.insert(id);
+        Some(id)
+    }
+
+    pub fn release(&mut self, id: u16) {
+        if self.used.remove(&id) {
+            self.free.push_back(id);
+        }
+    }
+}
+
+/// All proxied connections of a session, keyed by (origin, id).
+#[derive(Default)]
+pub struct ConnectionTable {
+    connections: HashMap<ConnectionKey, ProxyConnection>,
+    ids: IdAllocator,
+    /// Ids of dropped connections the target hasn't destroyed yet
+    retired: HashSet<u16>,
+}
+
+impl ConnectionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a connection created on this side under a fresh id.
+    pub fn insert_local(&mut self, connection: ProxyConnection) -> Option<ConnectionKey> {
+        let key = (HeaderOrigin::Control, self.ids.allocate()?);
+        self.connections.insert(key, connection);
+        Some(key)
+    }
+
+    /// Registers a connection announced by the target with the target's id.
+    pub fn insert_remote(&mut self, id: u16, connection: ProxyConnection) -> ConnectionKey {
+        let key = (HeaderOrigin::Target, id);
+        self.connections.insert(key, connection);
+        key
+    }
+
+    pub fn get(&self, key: &ConnectionKey) -> Option<&ProxyConnection> {
+        self.connections.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &ConnectionKey) -> Option<&mut ProxyConnection> {
+        self.connections.get_mut(key)
+    }
+
+    pub fn remove(&mut self, key: &ConnectionKey) -> Option<ProxyConnection> {
+        let connection = self.connections.remove(key)?;
+        if key.0 == HeaderOrigin::Control {
+            self.ids.release(key.1);
+        }
+        Some(connection)
+    }
+
+    /// Drops a connection but keeps its id taken until the target's Destroy
+    /// for it arrives, so late frames can't reach a new connection.
+    pub fn retire(&mut self, key: &ConnectionKey) -> Option<ProxyConnection> {
+        let connection = self.connections.remove(key)?;
+        if key.0 == HeaderOrigin::Control {
+            self.retired.insert(key.1);
+        }
+        Some(connection)
+    }
+
+    /// Frees the id of a retired connection once the target destroyed it.
+    pub fn release_retired(&mut self, key: &ConnectionKey) {
+        if key.0 == HeaderOrigin::Control && self.retired.remove(&key.1) {
+            self.ids.release(key.1);
+        }
+    }
+
+    /// The pending connects whose destination `error` names. An Error frame
+    /// has no header and so no connection id, the destination in its text is
+    /// all there is to go by.
+    pub fn pending_named(&self, error: &str) -> Vec<ConnectionKey> {
+        self.connections
+            .iter()
+            .filter(|(_, c)| c.pending.is_some() && !c.destination.is_empty())
+            .filter(|(_, c)| names(error, &c.destination))
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ConnectionKey, &ProxyConnection)> {
+        self.connections.iter()
+    }
+}
+
+/// Whether `destination` shows up in `text` on its own, so an error about
+/// host:8080 or ahost:80 doesn't name host:80.
+fn names(text: &str, destination: &str) -> bool {
+    text.match_indices(destination).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + destination.len()..].chars().next();
+        !before.is_some_and(|c| c.is_alphanumeric() || c == '.' || c == '-')
+            && !after.is_some_and(|c| c.is_ascii_digit())
+    })
+}
@@ -0,0 +1,295 @@
+use anyhow::Result;
+use bytes::BytesMut;
+#[cfg(feature = "tty")]
+use log::error;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::watch;
+
+use crate::session::{SessionId, SessionInfo, SessionTable};
+#[cfg(feature = "tty")]
+use crate::tty::Tty;
+
+type Input = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+type Output = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Ctrl-], the key that puts the attached session in the background.
+pub const DETACH_KEY: u8 = 0x1d;
+
+/// Why the console left a session.
+enum AttachEnd {
+    Detached,
+    Closed,
+    InputClosed,
+}
+
+/// The operator's side of the control: lists the sessions and connects the
+/// terminal to the one picked until it is detached again.
+pub struct Console {
+    sessions: SessionTable,
+    input: Option<Input>,
+    output: Option<Output>,
+    detach_key: u8,
+    /// Input that followed the detach key, for the picker
+    typeahead: BytesMut,
+    winsize: Option<watch::Receiver<(u16, u16)>>,
+    #[cfg(feature = "tty")]
+    tty: bool,
+}
+
+impl Console {
+    pub fn new(sessions: SessionTable) -> Self {
+        Self {
+            sessions,
+            input: None,
+            output: None,
+            detach_key: DETACH_KEY,
+            typeahead: BytesMut::new(),
+            winsize: None,
+            #[cfg(feature = "tty")]
+            tty: false,
+        }
+    }
+
+    /// Reads commands and shell input from `input` instead of stdin.
+    pub fn input<R>(&mut self, input: R) -> &mut Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.input = Some(BufReader::new(Box::new(input)));
+        self
+    }
+
+    /// Writes the session list and shell output to `output` instead of
+    /// stdout.
+    pub fn output<W>(&mut self, output: W) -> &mut Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    /// The key that detaches from a session, Ctrl-] by default.
+    pub fn detach_key(&mut self, detach_key: u8) -> &mut Self {
+        self.detach_key = detach_key;
+        self
+    }
+
+    /// Passes the terminal size on to the attached session whenever
+    /// `winsize` changes.
+    pub fn winsize(&mut self, winsize: watch::Receiver<(u16, u16)>) -> &mut Self {
+        self.winsize = Some(winsize);
+        self
+    }
+
+    /// Puts the terminal in raw mode while a session is attached and follows
+    /// its size.
+    #[cfg(feature = "tty")]
+    pub fn tty(&mut self) -> &mut Self {
+        self.tty = true;
+        match Tty::watch_winsize() {
+            Ok(winsize) => self.winsize = Some(winsize),
+            Err(e) => error!("Can't follow the window size: {}", e),
+        }
+        self
+    }
+
+    /// Runs until the input is closed or the operator quits.
+    pub async fn run(mut self) -> Result<()> {
+        let mut input: Input = match self.input.take() {
+            Some(input) => input,
+            // https://github.com/tokio-rs/tokio/issues/2466
+            None => BufReader::new(Box::new(tokio_fd::AsyncFd::try_from(libc::STDIN_FILENO)?)),
+        };
+        let mut output = self
+            .output
+            .take()
+            .unwrap_or_else(|| Box::new(tokio::io::stdout()));
+
+        loop {
+            let sessions = self.sessions.list().await;
+            output
+                .write_all(Self::format_list(&sessions).as_bytes())
+                .await?;
+            output.write_all(b"session> ").await?;
+            output.flush().await?;
+
+            let line = match self.read_line(&mut input).await? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            match line.trim() {
+                "" | "l" | "list" => {}
+                "q" | "quit" => return Ok(()),
+                command => match command.parse::<SessionId>() {
+                    Ok(id) => {
+                        if !self.attach(id, &mut input, &mut output).await? {
+                            return Ok(());
+                        }
+                    }
+                    Err(_) => {
+                        output
+                            .write_all(b"Enter a session id, list or quit\n")
+                            .await?
+                    }
+                },
+            }
+        }
+    }
+
+    /// The next line for the picker, starting with what was typed after the
+    /// detach key. `None` once the input is closed.
+    async fn read_line(&mut self, input: &mut Input) -> Result<Option<String>> {
+        let line = match self.typeahead.iter().position(|b| *b == b'\n') {
+            Some(i) => self.typeahead.split_to(i + 1).to_vec(),
+            None => {
+                let mut line = self.typeahead.split().to_vec();
+                if input.read_until(b'\n', &mut line).await? == 0 && line.is_empty() {
+                    return Ok(None);
+                }
+                line
+            }
+        };
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    /// Connects the terminal to session `id` until the session ends or the
+    /// detach key is pressed. Returns false when the input was closed.
+    async fn attach(
+        &mut self,
+        id: SessionId,
+        input: &mut Input,
+        output: &mut Output,
+    ) -> Result<bool> {
+        let mut io = match self.sessions.attach(id).await {
+            Ok(io) => io,
+            Err(e) => {
+                output.write_all(format!("{}\n", e).as_bytes()).await?;
+                return Ok(true);
+            }
+        };
+        output
+            .write_all(
+                format!(
+                    "Attached to session {}, {} detaches\n",
+                    id,
+                    Self::format_key(self.detach_key)
+                )
+                .as_bytes(),
+            )
+            .await?;
+
+        #[cfg(feature = "tty")]
+        let tty = self.tty.then(Tty::new);
+
+        // The terminal may have been resized while the session was detached
+        if let Some(winsize) = &mut self.winsize {
+            let (rows, cols) = *winsize.borrow_and_update();
+            self.sessions.resize(id, rows, cols).await;
+        }
+
+        // Catch up on what the session printed in the background
+        output.write_all(&io.scrollback).await?;
+        output.flush().await?;
+
+        // Input typed after the detach key and the session id comes first
+        let mut from_input = self.typeahead.split();
+        let end = loop {
+            if !from_input.is_empty() {
+                let data = from_input.split();
+                if let Some(i) = data.iter().position(|b| *b == self.detach_key) {
+                    io.input.write_all(&data[..i]).await?;
+                    self.typeahead.extend_from_slice(&data[i + 1..]);
+                    break AttachEnd::Detached;
+                }
+                io.input.write_all(&data).await?;
+            }
+
+            from_input.reserve(4096);
+            tokio::select! {
+                n = input.read_buf(&mut from_input) => {
+                    if n? == 0 {
+                        break AttachEnd::InputClosed;
+                    }
+                }
+                data = io.output.recv() => match data {
+                    Some(data) => {
+                        output.write_all(&data).await?;
+                        output.flush().await?;
+                    }
+                    None => break AttachEnd::Closed,
+                },
+                Some((rows, cols)) = Self::winsize_changed(&mut self.winsize) => {
+                    self.sessions.resize(id, rows, cols).await;
+                }
+            }
+        };
+
+        // Back to a cooked terminal for the picker
+        #[cfg(feature = "tty")]
+        drop(tty);
+        match end {
+            AttachEnd::Detached => {
+                self.sessions.detach(io).await;
+                output
+                    .write_all(format!("\nDetached from session {}\n", id).as_bytes())
+                    .await?;
+                Ok(true)
+            }
+            AttachEnd::Closed => {
+                output
+                    .write_all(format!("\nSession {} closed\n", id).as_bytes())
+                    .await?;
+                Ok(true)
+            }
+            AttachEnd::InputClosed => {
+                self.sessions.detach(io).await;
+                Ok(false)
+            }
+        }
+    }
+
+    /// The next terminal size, never returns without a terminal to follow.
+    async fn winsize_changed(
+        winsize: &mut Option<watch::Receiver<(u16, u16)>>,
+    ) -> Option<(u16, u16)> {
+        if let Some(winsize) = winsize {
+            if winsize.changed().await.is_ok() {
+                return Some(*winsize.borrow_and_update());
+            }
+        }
+        std::future::pending().await
+    }
+
+    /// How a control key is written, like ^] for 0x1d.
+    fn format_key(key: u8) -> String {
+        match key {
+            0..=0x1f => format!("^{}", char::from(key + 0x40)),
+            0x7f => "^?".to_string(),
+            _ => char::from(key).to_string(),
+        }
+    }
+
+    fn format_list(sessions: &[SessionInfo]) -> String {
+        if sessions.is_empty() {
+            return "No sessions, press enter to refresh\n".to_string();
+        }
+        let mut list = format!("{:>4}  {:<24}  {:>9}\n", "ID", "REMOTE", "UPTIME");
+        for session in sessions {
+            list.push_str(&format!(
+                "{:>4}  {:<24}  {:>9}{}\n",
+                session.id,
+                session.remote_address,
+                Self::format_uptime(session.uptime()),
+                if session.attached { "  attached" } else { "" },
+            ));
+        }
+        list
+    }
+
+    fn format_uptime(uptime: Duration) -> String {
+        let secs = uptime.as_secs();
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
@@ -1,176 +1,317 @@
-use anyhow::{bail, Context, Result};
-use log::debug;
-use std::fs::File;
-use std::io::Read;
+use anyhow::{bail, Result};
+use bytes::BytesMut;
+use log::{debug, error, info};
+use std::io;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-use tokio_native_tls::native_tls::TlsAcceptor as NativeTlsAcceptor;
-use tokio_native_tls::native_tls::{Identity, Protocol};
-use tokio_native_tls::TlsAcceptor as TokioTlsAcceptor;
-use tokio_native_tls::TlsStream;
 
 use crate::broker::Broker;
-use crate::message::{DataType, Message};
+use crate::escape::ESCAPE_CHAR;
+use crate::forward::ForwardSpec;
+use crate::protocol::{ControlProtocol, Event};
+use crate::session::SessionTable;
+use crate::transport::{Acceptor, Incoming, TlsListener, Transport};
 #[cfg(feature = "tty")]
 use crate::tty::Tty;
 
-type MyTlsStream = Arc<Mutex<Option<TlsStream<TcpStream>>>>;
-
-pub struct Control {
-    message_data_size: u16,
-    shell: String,
-    env: Vec<String>,
+// Pause after a failed accept, doubled while accepts keep failing, e.g.
+// while out of file descriptors
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// What every session is set up with, cloned into each handshake task.
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub message_data_size: u16,
+    pub shell: String,
+    pub env: Vec<String>,
     pub proxy_address: Option<SocketAddr>,
-    listener: TcpListener,
-    acceptor: TokioTlsAcceptor,
-    pub stream: MyTlsStream,
+    /// How long a SOCKS connect waits for the target before it fails
+    pub connect_timeout: Duration,
+    /// How long dialing the local destination of a remote forward may take
+    pub dial_timeout: Duration,
+    /// Grants SOCKS connects without waiting for the target
+    pub optimistic_connect: bool,
+    pub local_forwards: Vec<ForwardSpec>,
+    pub remote_forwards: Vec<ForwardSpec>,
+    /// Starts escape sequences on stdin, `None` turns them off
+    pub escape_char: Option<u8>,
 }
 
-impl Control {
-    pub async fn new(address: SocketAddr, key_file: &Path) -> Result<Self> {
-        let listener: TcpListener = TcpListener::bind(&address).await?;
+impl SessionConfig {
+    /// Runs the handshake on `stream` and builds a broker set up with this
+    /// config, forwards included. Doesn't need a `Control`, so handshake
+    /// tasks can own a clone.
+    pub async fn establish<T: Transport>(
+        &self,
+        mut stream: T,
+        remote_address: String,
+    ) -> Result<Broker<T>> {
+        let protocol = self.handle_client(&mut stream).await?;
+        Ok(Broker::new(self, stream, protocol, remote_address))
+    }
 
-        // openssl pkcs12 -export -out identity.pfx -inkey key.pem -in cert.pem
-        let mut file = File::open(key_file)?;
-        let mut identity = vec![];
-        file.read_to_end(&mut identity)?;
-        let identity = Identity::from_pkcs12(&identity, "")?;
+    /// Drives the handshake and Init sequence of a fresh connection.
+    pub async fn handle_client<T: Transport>(&self, stream: &mut T) -> Result<ControlProtocol> {
+        debug!("Got connection");
 
-        let acceptor = NativeTlsAcceptor::builder(identity)
-            .min_protocol_version(Some(Protocol::Sslv3))
-            .build()?;
+        #[cfg(feature = "tty")]
+        let (rows, cols) = Tty::get_term_size();
+        #[cfg(not(feature = "tty"))]
+        let (rows, cols) = (0, 0);
+
+        let mut protocol = ControlProtocol::new()
+            .shell(self.shell.clone())
+            .env(self.env.clone())
+            .winsize(rows, cols)
+            .message_data_size(self.message_data_size);
+        protocol.start();
+
+        let mut buf = BytesMut::with_capacity(1024);
+        loop {
+            while let Some(data) = protocol.poll_transmit() {
+                stream.write_all(&data).await?;
+            }
+            if let Some(Event::Established { .. }) = protocol.poll_event() {
+                debug!("Protocol ok");
+                return Ok(protocol);
+            }
+
+            buf.clear();
+            if stream.read_buf(&mut buf).await? == 0 {
+                bail!("Connection closed during handshake");
+            }
+            protocol.receive(&buf)?;
+        }
+    }
 
-        let acceptor = TokioTlsAcceptor::from(acceptor);
+    /// Like `establish`, but only the first session to get through its
+    /// handshake gets the forwards, the others start without any.
+    async fn establish_once<T: Transport>(
+        &self,
+        mut stream: T,
+        remote_address: String,
+        forwards_taken: &AtomicBool,
+    ) -> Result<Broker<T>> {
+        let protocol = self.handle_client(&mut stream).await?;
+        if forwards_taken.swap(true, Ordering::SeqCst) {
+            let config = Self {
+                proxy_address: None,
+                local_forwards: vec![],
+                remote_forwards: vec![],
+                ..self.clone()
+            };
+            return Ok(Broker::new(&config, stream, protocol, remote_address));
+        }
+        Ok(Broker::new(self, stream, protocol, remote_address))
+    }
 
-        Ok(Self {
+    async fn establish_incoming<T: Transport>(
+        &self,
+        incoming: Incoming<T>,
+        forwards_taken: &AtomicBool,
+    ) -> Result<Broker<T>> {
+        let remote_address = incoming.remote_address.clone();
+        let stream = incoming.stream().await?;
+        self.establish_once(stream, remote_address, forwards_taken)
+            .await
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
             message_data_size: u16::MAX,
             shell: "/bin/sh".to_string(),
             env: vec!["PATH=/bin:/usr/bin/".to_string()],
             proxy_address: None,
-            listener,
-            acceptor,
-            stream: Arc::new(Mutex::new(None)),
-        })
+            connect_timeout: Duration::from_secs(10),
+            dial_timeout: Duration::from_secs(10),
+            optimistic_connect: false,
+            local_forwards: vec![],
+            remote_forwards: vec![],
+            escape_char: Some(ESCAPE_CHAR),
+        }
     }
+}
 
-    pub fn shell<'a>(&'a mut self, shell: String) -> &'a mut Self {
-        self.shell = shell;
-        self
-    }
+/// Accept errors that only concern one connection or pass once file
+/// descriptors or memory free up.
+fn is_transient(e: &anyhow::Error) -> bool {
+    let e = match e.downcast_ref::<io::Error>() {
+        Some(e) => e,
+        None => return false,
+    };
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    ) || matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
 
-    pub fn env<'a>(&'a mut self, env: Vec<String>) -> &'a mut Self {
-        self.env = env;
-        self
-    }
+/// Accepts targets and sets up a session for each.
+///
+/// The SOCKS proxy and the local and remote forwards set here belong to the
+/// first session established. Every other session starts without them: the
+/// local ports are taken already and each target would be asked for the same
+/// remote ports again. They aren't handed on when that session ends; other
+/// sessions add their own forwards with `~C`.
+pub struct Control<A = TlsListener> {
+    config: SessionConfig,
+    acceptor: A,
+    /// Set once a session got the forwards
+    forwards_taken: Arc<AtomicBool>,
+}
 
-    pub fn proxy<'a>(&'a mut self, proxy_address: Option<SocketAddr>) -> &'a mut Self {
-        self.proxy_address = proxy_address;
-        self
+impl Control<TlsListener> {
+    pub async fn new(address: SocketAddr, key_file: &Path) -> Result<Self> {
+        Ok(Self::with_acceptor(
+            TlsListener::bind(address, key_file).await?,
+        ))
     }
 
-    pub async fn accept(&mut self) -> Result<Broker> {
-        let (stream, remote_address) = self.listener.accept().await?;
-        let acceptor = self.acceptor.clone();
-        let stream = acceptor.accept(stream).await?;
-        self.stream = Arc::new(Mutex::new(Some(stream)));
-        self.handle_client().await?;
-        Broker::new(self, remote_address).await
+    /// The address the control listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.acceptor.local_addr()
     }
+}
 
-    pub async fn handle_client(&mut self) -> Result<()> {
-        debug!("Got connection");
-
-        self.negotiate_protocol().await?;
-        debug!("Protocol ok");
-
-        // Send interactive
-        Message::new()
-            .data_type(DataType::Init)
-            .data(vec![0x1])
-            .push(&mut self.stream)
-            .await?;
-
-        let _message = Message::pull(&mut self.stream).await?;
-
-        // Initial shell data
-        Message::new()
-            .data_type(DataType::Init)
-            .data(self.shell.as_bytes().to_vec())
-            .push(&mut self.stream)
-            .await?;
-
-        // Env
-        Message::new()
-            .data_type(DataType::Init)
-            .data(self.env.join(" ").as_bytes().to_vec())
-            .push(&mut self.stream)
-            .await?;
-
-        // Termios
-        #[cfg(feature = "tty")]
-        let (term_width, term_height) = Tty::get_term_size();
-        #[cfg(not(feature = "tty"))]
-        let (term_width, term_height) = (0, 0);
+impl<A: Acceptor> Control<A> {
+    /// Takes targets from `acceptor` instead of the TLS listener.
+    pub fn with_acceptor(acceptor: A) -> Self {
+        Self {
+            config: SessionConfig::default(),
+            acceptor,
+            forwards_taken: Arc::new(AtomicBool::new(false)),
+        }
+    }
 
-        let mut data = Vec::new();
-        data.append(&mut u16::to_be_bytes(term_width).to_vec());
-        data.append(&mut u16::to_be_bytes(term_height).to_vec());
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
 
-        Message::new()
-            .data_type(DataType::Init)
-            .data(data)
-            .push(&mut self.stream)
-            .await?;
+    pub fn message_data_size(&mut self, message_data_size: u16) -> &mut Self {
+        self.config.message_data_size = message_data_size;
+        self
+    }
 
-        Ok(())
+    pub fn shell(&mut self, shell: String) -> &mut Self {
+        self.config.shell = shell;
+        self
     }
 
-    pub async fn negotiate_protocol(&mut self) -> Result<()> {
-        let stream = self.stream.clone();
-        let mut stream = stream.lock().await;
-        let stream = stream.as_mut().context("error")?;
+    pub fn env(&mut self, env: Vec<String>) -> &mut Self {
+        self.config.env = env;
+        self
+    }
 
-        // Send proto major
-        stream.write_all(&u16::to_be_bytes(1)).await?;
+    pub fn proxy(&mut self, proxy_address: Option<SocketAddr>) -> &mut Self {
+        self.config.proxy_address = proxy_address;
+        self
+    }
 
-        // Send proto minor
-        stream.write_all(&u16::to_be_bytes(0)).await?;
+    pub fn local_forwards(&mut self, local_forwards: Vec<ForwardSpec>) -> &mut Self {
+        self.config.local_forwards = local_forwards;
+        self
+    }
 
-        let mut buf = [0u8; 2];
+    pub fn remote_forwards(&mut self, remote_forwards: Vec<ForwardSpec>) -> &mut Self {
+        self.config.remote_forwards = remote_forwards;
+        self
+    }
 
-        // Recv proto major
-        stream.read_exact(&mut buf).await?;
-        let proto_major = u16::from_be_bytes(buf);
+    pub fn connect_timeout(&mut self, connect_timeout: Duration) -> &mut Self {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
 
-        // Recv proto minor
-        stream.read_exact(&mut buf).await?;
-        let proto_minor = u16::from_be_bytes(buf);
+    pub fn dial_timeout(&mut self, dial_timeout: Duration) -> &mut Self {
+        self.config.dial_timeout = dial_timeout;
+        self
+    }
 
-        debug!("Proto {}.{}", proto_major, proto_minor);
+    /// Replies to SOCKS clients as soon as the connect is sent to the
+    /// target. The C target only speaks up when a connect fails, so without
+    /// this a client that has to send first, like an HTTP client, only gets
+    /// its reply from the timeout. With it a closed port looks open and the
+    /// connection is closed right after.
+    pub fn optimistic_connect(&mut self, optimistic_connect: bool) -> &mut Self {
+        self.config.optimistic_connect = optimistic_connect;
+        self
+    }
 
-        // Send desired data size
-        stream
-            .write_all(&u16::to_be_bytes(self.message_data_size))
-            .await?;
+    pub fn escape_char(&mut self, escape_char: Option<u8>) -> &mut Self {
+        self.config.escape_char = escape_char;
+        self
+    }
 
-        // Recv desired data size
-        stream.read_exact(&mut buf).await?;
-        let data_size = u16::from_be_bytes(buf);
+    /// Waits for one target and runs its handshake.
+    pub async fn accept(&mut self) -> Result<Broker<A::Stream>> {
+        let incoming = self.acceptor.accept().await?;
+        self.config
+            .establish_incoming(incoming, &self.forwards_taken)
+            .await
+    }
 
-        if data_size < 1024 {
-            bail!("Can't agree on a message size");
-        }
+    /// Takes a target that connected some other way than through this
+    /// control's acceptor, e.g. over a socket passed in by a launcher, and
+    /// sets up a session for it with this control's settings.
+    pub async fn establish<T: Transport>(
+        &self,
+        stream: T,
+        remote_address: String,
+    ) -> Result<Broker<T>> {
+        self.config
+            .establish_once(stream, remote_address, &self.forwards_taken)
+            .await
+    }
 
-        if data_size < self.message_data_size {
-            self.message_data_size = data_size;
+    /// Keeps accepting targets. Every handshake runs in its own task and
+    /// every established session goes into `sessions`.
+    ///
+    /// Failed accepts are retried with a growing pause as long as they may
+    /// pass, any other accept error ends it.
+    pub async fn serve(&mut self, sessions: SessionTable) -> Result<()> {
+        let mut backoff = Duration::ZERO;
+        loop {
+            let incoming = match self.acceptor.accept().await {
+                Ok(incoming) => {
+                    backoff = Duration::ZERO;
+                    incoming
+                }
+                Err(e) if is_transient(&e) => {
+                    error!("Accept failed: {}", e);
+                    backoff = (backoff * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+                    tokio::time::sleep(backoff).await;
+                    continue;
+                }
+                Err(e) => bail!("Accept failed: {}", e),
+            };
+            debug!("Connection from {}", incoming.remote_address);
+
+            let config = self.config.clone();
+            let forwards_taken = self.forwards_taken.clone();
+            let sessions = sessions.clone();
+            tokio::spawn(async move {
+                let remote_address = incoming.remote_address.clone();
+                match config.establish_incoming(incoming, &forwards_taken).await {
+                    Ok(broker) => {
+                        let id = sessions.insert(broker).await;
+                        info!("Session {} from {} established", id, remote_address);
+                    }
+                    Err(e) => error!("Handshake with {} failed: {}", remote_address, e),
+                }
+            });
         }
-
-        debug!("Data size {}", self.message_data_size);
-
-        Ok(())
     }
 }
@@ -0,0 +1,166 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// The default escape character, as in OpenSSH.
+pub const ESCAPE_CHAR: u8 = b'~';
+
+const PROMPT: &[u8] = b"\r\nrevsh> ";
+
+/// What a chunk of keyboard input asks for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Bytes for the target
+    Send(Bytes),
+    /// Bytes to show locally, like the command line being typed
+    Echo(Bytes),
+    Disconnect,
+    Help,
+    ListConnections,
+    /// A line entered at the command line
+    Command(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Normal,
+    /// Right after a newline, where the escape character is recognised
+    LineStart,
+    /// After the escape character
+    Escape,
+    /// Typing a command line
+    Command,
+}
+
+/// Picks OpenSSH style escape sequences out of keyboard input without any
+/// IO. The escape character only counts right after a newline or at the
+/// very start.
+pub struct EscapeParser {
+    escape_char: u8,
+    state: State,
+    line: Vec<u8>,
+}
+
+impl EscapeParser {
+    pub fn new(escape_char: u8) -> Self {
+        Self {
+            escape_char,
+            state: State::LineStart,
+            line: vec![],
+        }
+    }
+
+    pub fn escape_char(&self) -> u8 {
+        self.escape_char
+    }
+
+    /// Splits `data` into what goes to the target and the escapes in it.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Action> {
+        let mut actions = vec![];
+        let mut send = BytesMut::new();
+        let mut echo = BytesMut::new();
+        for &byte in data {
+            match self.state {
+                State::Normal | State::LineStart => {
+                    if self.state == State::LineStart && byte == self.escape_char {
+                        self.state = State::Escape;
+                        continue;
+                    }
+                    send.put_u8(byte);
+                    self.state = Self::after(byte);
+                }
+                State::Escape => {
+                    let action = match byte {
+                        b'.' => Action::Disconnect,
+                        b'?' => Action::Help,
+                        b'#' => Action::ListConnections,
+                        b'C' => {
+                            self.state = State::Command;
+                            self.line.clear();
+                            echo.put_slice(PROMPT);
+                            Self::flush(&mut actions, &mut send, &mut echo);
+                            continue;
+                        }
+                        _ => {
+                            // Not an escape after all, both go to the target
+                            if byte != self.escape_char {
+                                send.put_u8(self.escape_char);
+                            }
+                            send.put_u8(byte);
+                            self.state = Self::after(byte);
+                            continue;
+                        }
+                    };
+                    Self::flush(&mut actions, &mut send, &mut echo);
+                    actions.push(action);
+                    self.state = State::LineStart;
+                }
+                State::Command => match byte {
+                    b'\r' | b'\n' => {
+                        echo.put_slice(b"\r\n");
+                        Self::flush(&mut actions, &mut send, &mut echo);
+                        let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                        if !line.is_empty() {
+                            actions.push(Action::Command(line));
+                        }
+                        self.state = State::LineStart;
+                    }
+                    // Backspace and delete
+                    0x08 | 0x7f if self.line.pop().is_some() => echo.put_slice(b"\x08 \x08"),
+                    // Ctrl-C and Ctrl-U give up on the line
+                    0x03 | 0x15 => {
+                        echo.put_slice(b"\r\n");
+                        self.state = State::LineStart;
+                    }
+                    0x20..=0x7e => {
+                        self.line.push(byte);
+                        echo.put_u8(byte);
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Self::flush(&mut actions, &mut send, &mut echo);
+        actions
+    }
+
+    /// The text `~?` shows.
+    pub fn help(&self) -> String {
+        let escape_char = char::from(self.escape_char);
+        [
+            "Supported escape sequences:".to_string(),
+            format!(" {}.  - terminate session", escape_char),
+            format!(" {}?  - this message", escape_char),
+            format!(" {}#  - list forwarded connections", escape_char),
+            format!(" {}C  - open a command line", escape_char),
+            format!(
+                " {}{}  - send the escape character by typing it twice",
+                escape_char, escape_char
+            ),
+            "(Note that escapes are only recognized immediately after newline.)".to_string(),
+        ]
+        .join("\r\n")
+            + "\r\n"
+    }
+
+    fn after(byte: u8) -> State {
+        match byte {
+            b'\r' | b'\n' => State::LineStart,
+            _ => State::Normal,
+        }
+    }
+
+    /// Keeps the order of sent and echoed bytes around an action.
+    fn flush(actions: &mut Vec<Action>, send: &mut BytesMut, echo: &mut BytesMut) {
+        if !send.is_empty() {
+            actions.push(Action::Send(send.split().freeze()));
+        }
+        if !echo.is_empty() {
+            actions.push(Action::Echo(echo.split().freeze()));
+        }
+    }
+}
+
+impl Default for EscapeParser {
+    fn default() -> Self {
+        Self::new(ESCAPE_CHAR)
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{anyhow, bail, Context, Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// An ssh style `[bind:]port:host:hostport` forward specification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardSpec {
+    pub bind_address: String,
+    pub port: u16,
+    pub host: String,
+    pub host_port: u16,
+}
+
+impl ForwardSpec {
+    /// The "bind:port" address the listening side binds to.
+    pub fn listen_address(&self) -> String {
+        format!("{}:{}", Self::bracket(&self.bind_address), self.port)
+    }
+
+    /// The "host:hostport" string of the fixed destination.
+    pub fn connection_string(&self) -> String {
+        format!("{}:{}", Self::bracket(&self.host), self.host_port)
+    }
+
+    fn bracket(address: &str) -> String {
+        if address.contains(':') {
+            format!("[{}]", address)
+        } else {
+            address.to_string()
+        }
+    }
+
+    /// Splits on ':' but keeps bracketed IPv6 addresses in one piece.
+    fn split(spec: &str) -> Result<Vec<String>> {
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut in_brackets = false;
+        for c in spec.chars() {
+            match c {
+                '[' if !in_brackets => in_brackets = true,
+                ']' if in_brackets => in_brackets = false,
+                ':' if !in_brackets => fields.push(std::mem::take(&mut field)),
+                _ => field.push(c),
+            }
+        }
+        if in_brackets {
+            bail!("Unbalanced brackets in forward {}", spec);
+        }
+        fields.push(field);
+        Ok(fields)
+    }
+}
+
+impl FromStr for ForwardSpec {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let fields = Self::split(spec)?;
+        let (bind_address, fields) = match fields.len() {
+            3 => ("127.0.0.1".to_string(), &fields[..]),
+            4 => (fields[0].clone(), &fields[1..]),
+            _ => bail!("Forward {} is not [bind:]port:host:hostport", spec),
+        };
+        if fields[1].is_empty() {
+            bail!("Forward {} has no destination host", spec);
+        }
+        Ok(Self {
+            bind_address,
+            port: fields[0]
+                .parse()
+                .with_context(|| format!("Bad port in forward {}", spec))?,
+            host: fields[1].clone(),
+            host_port: fields[2]
+                .parse()
+                .with_context(|| format!("Bad host port in forward {}", spec))?,
+        })
+    }
+}
+
+impl fmt::Display for ForwardSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.listen_address(), self.connection_string())
+    }
+}
+
+/// A line from the `~C` command line, as in OpenSSH.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardCommand {
+    Help,
+    /// -D [bind:]port
+    Dynamic(String),
+    Local(ForwardSpec),
+    Remote(ForwardSpec),
+    /// -KD [bind:]port
+    CancelDynamic(String),
+    /// -KL [bind:]port
+    CancelLocal(String),
+    /// -KR [bind:]port
+    CancelRemote(String),
+}
+
+impl ForwardCommand {
+    pub const HELP: &'static str = "Commands:\r
+      -L[bind_address:]port:host:hostport    Request local forward\r
+      -R[bind_address:]port:host:hostport    Request remote forward\r
+      -D[bind_address:]port                  Request dynamic forward\r
+      -KL[bind_address:]port                 Cancel local forward\r
+      -KR[bind_address:]port                 Cancel remote forward\r
+      -KD[bind_address:]port                 Cancel dynamic forward\r
+      -h                                     This message\r
+";
+
+    /// Turns "[bind:]port" into the "bind:port" a listener is known by.
+    fn listen_address(address: &str) -> Result<String> {
+        let fields = ForwardSpec::split(address)?;
+        let (bind_address, port) = match &fields[..] {
+            [port] => ("127.0.0.1", port),
+            [bind_address, port] => (bind_address.as_str(), port),
+            _ => bail!("Address {} is not [bind:]port", address),
+        };
+        let port: u16 = port
+            .parse()
+            .with_context(|| format!("Bad port in {}", address))?;
+        Ok(format!("{}:{}", ForwardSpec::bracket(bind_address), port))
+    }
+}
+
+impl FromStr for ForwardCommand {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let line = line.trim();
+        if matches!(line, "-h" | "?" | "help") {
+            return Ok(Self::Help);
+        }
+        // The argument may follow the option with or without a space
+        let option_len = if line.starts_with("-K") { 3 } else { 2 };
+        let (option, argument) = match line.get(..option_len) {
+            Some(option) => (option, line[option_len..].trim()),
+            None => bail!("Invalid command: {}", line),
+        };
+        let argument = || match argument {
+            "" => Err(anyhow!("Missing argument for {}", option)),
+            argument => Ok(argument),
+        };
+        Ok(match option {
+            "-D" => Self::Dynamic(Self::listen_address(argument()?)?),
+            "-L" => Self::Local(argument()?.parse()?),
+            "-R" => Self::Remote(argument()?.parse()?),
+            "-KD" => Self::CancelDynamic(Self::listen_address(argument()?)?),
+            "-KL" => Self::CancelLocal(Self::listen_address(argument()?)?),
+            "-KR" => Self::CancelRemote(Self::listen_address(argument()?)?),
+            _ => bail!("Invalid command: {}", line),
+        })
+    }
+}
@@ -0,0 +1,222 @@
+use bytes::Bytes;
+
+use crate::message::{
+    ConnectionHeaderType, DataType, HeaderOrigin, Message, ProtocolError, ProxyHeaderType,
+    ProxyType,
+};
+
+/// A message with its header fields checked against its data type, so only
+/// combinations the protocol knows can be represented.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Init(Bytes),
+    Tty(Bytes),
+    Winresize {
+        rows: u16,
+        cols: u16,
+    },
+    /// `spec` is sent as is, a trailing NUL from the target is dropped.
+    ProxyCreate {
+        origin: HeaderOrigin,
+        id: u16,
+        proxy_type: ProxyType,
+        spec: String,
+    },
+    ProxyDestroy {
+        origin: HeaderOrigin,
+        id: u16,
+    },
+    ProxyReport {
+        origin: HeaderOrigin,
+        id: u16,
+        proxy_type: ProxyType,
+        data: Bytes,
+    },
+    /// `destination` goes on the wire NUL terminated, as the C revsh sends
+    /// it. One trailing NUL is dropped when reading.
+    ConnectionCreate {
+        origin: HeaderOrigin,
+        id: u16,
+        proxy_type: ProxyType,
+        destination: String,
+    },
+    ConnectionDestroy {
+        origin: HeaderOrigin,
+        id: u16,
+    },
+    ConnectionData {
+        origin: HeaderOrigin,
+        id: u16,
+        data: Bytes,
+    },
+    ConnectionDormant {
+        origin: HeaderOrigin,
+        id: u16,
+    },
+    ConnectionActive {
+        origin: HeaderOrigin,
+        id: u16,
+    },
+    Nop,
+    Error(String),
+}
+
+impl TryFrom<Message> for Frame {
+    type Error = ProtocolError;
+
+    fn try_from(message: Message) -> Result<Self, ProtocolError> {
+        let origin = HeaderOrigin::from(message.header_origin);
+        let id = message.header_id;
+        let frame = match message.data_type {
+            DataType::Init => Frame::Init(message.data),
+            DataType::Tty => Frame::Tty(message.data),
+            DataType::Winresize => {
+                if message.data.len() != 4 {
+                    return Err(ProtocolError::WinresizeLen(message.data.len()));
+                }
+                Frame::Winresize {
+                    rows: u16::from_be_bytes([message.data[0], message.data[1]]),
+                    cols: u16::from_be_bytes([message.data[2], message.data[3]]),
+                }
+            }
+            DataType::Proxy => {
+                if origin == HeaderOrigin::Unknown {
+                    return Err(ProtocolError::HeaderOrigin(message.header_origin));
+                }
+                match ProxyHeaderType::from(message.header_type) {
+                    ProxyHeaderType::Create => Frame::ProxyCreate {
+                        origin,
+                        id,
+                        proxy_type: ProxyType::try_from(message.header_proxy_type)?,
+                        spec: c_string(&message.data),
+                    },
+                    ProxyHeaderType::Destroy => Frame::ProxyDestroy { origin, id },
+                    ProxyHeaderType::Report => Frame::ProxyReport {
+                        origin,
+                        id,
+                        proxy_type: ProxyType::try_from(message.header_proxy_type)?,
+                        data: message.data,
+                    },
+                    ProxyHeaderType::Unknown => {
+                        return Err(ProtocolError::HeaderType(message.header_type))
+                    }
+                }
+            }
+            DataType::Connection => {
+                if origin == HeaderOrigin::Unknown {
+                    return Err(ProtocolError::HeaderOrigin(message.header_origin));
+                }
+                match ConnectionHeaderType::from(message.header_type) {
+                    ConnectionHeaderType::Create => Frame::ConnectionCreate {
+                        origin,
+                        id,
+                        proxy_type: ProxyType::try_from(message.header_proxy_type)?,
+                        destination: c_string(&message.data),
+                    },
+                    ConnectionHeaderType::Destroy => Frame::ConnectionDestroy { origin, id },
+                    // header_proxy_type is on the wire but means nothing here
+                    ConnectionHeaderType::Data => Frame::ConnectionData {
+                        origin,
+                        id,
+                        data: message.data,
+                    },
+                    ConnectionHeaderType::Dormant => Frame::ConnectionDormant { origin, id },
+                    ConnectionHeaderType::Active => Frame::ConnectionActive { origin, id },
+                    ConnectionHeaderType::Unknown => {
+                        return Err(ProtocolError::HeaderType(message.header_type))
+                    }
+                }
+            }
+            DataType::Nop => Frame::Nop,
+            DataType::Error => Frame::Error(String::from_utf8_lossy(&message.data).to_string()),
+            DataType::Unknown => return Err(ProtocolError::DataType(DataType::Unknown.value())),
+        };
+        Ok(frame)
+    }
+}
+
+/// The C revsh builds its strings with the NUL, which isn't part of them.
+fn c_string(data: &[u8]) -> String {
+    let data = data.strip_suffix(b"\0").unwrap_or(data);
+    String::from_utf8_lossy(data).to_string()
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        let message = Message::new();
+        match frame {
+            Frame::Init(data) => message.data_type(DataType::Init).data(data),
+            Frame::Tty(data) => message.data_type(DataType::Tty).data(data),
+            Frame::Winresize { rows, cols } => {
+                let mut data = Vec::with_capacity(4);
+                data.extend(u16::to_be_bytes(rows));
+                data.extend(u16::to_be_bytes(cols));
+                message.data_type(DataType::Winresize).data(data)
+            }
+            Frame::ProxyCreate {
+                origin,
+                id,
+                proxy_type,
+                spec,
+            } => message
+                .data_type(DataType::Proxy)
+                .header_type(ProxyHeaderType::Create)
+                .header_origin(origin)
+                .header_id(id)
+                .header_proxy_type(proxy_type)
+                .data(spec),
+            Frame::ProxyDestroy { origin, id } => message
+                .data_type(DataType::Proxy)
+                .header_type(ProxyHeaderType::Destroy)
+                .header_origin(origin)
+                .header_id(id),
+            Frame::ProxyReport {
+                origin,
+                id,
+                proxy_type,
+                data,
+            } => message
+                .data_type(DataType::Proxy)
+                .header_type(ProxyHeaderType::Report)
+                .header_origin(origin)
+                .header_id(id)
+                .header_proxy_type(proxy_type)
+                .data(data),
+            Frame::ConnectionCreate {
+                origin,
+                id,
+                proxy_type,
+                destination,
+            } => message
+                .data_type(DataType::Connection)
+                .header_type(ConnectionHeaderType::Create)
+                .header_origin(origin)
+                .header_id(id)
+                .header_proxy_type(proxy_type)
+                .data(format!("{}\0", destination)),
+            Frame::ConnectionDestroy { origin, id } => message
+                .data_type(DataType::Connection)
+                .header_type(ConnectionHeaderType::Destroy)
+                .header_origin(origin)
+                .header_id(id),
+            Frame::ConnectionData { origin, id, data } => message
+                .data_type(DataType::Connection)
+                .header_type(ConnectionHeaderType::Data)
+                .header_origin(origin)
+                .header_id(id)
+                .data(data),
+            Frame::ConnectionDormant { origin, id } => message
+                .data_type(DataType::Connection)
+                .header_type(ConnectionHeaderType::Dormant)
+                .header_origin(origin)
+                .header_id(id),
+            Frame::ConnectionActive { origin, id } => message
+                .data_type(DataType::Connection)
+                .header_type(ConnectionHeaderType::Active)
+                .header_origin(origin)
+                .header_id(id),
+            Frame::Nop => message.data_type(DataType::Nop),
+            Frame::Error(error) => message.data_type(DataType::Error).data(error),
+        }
+    }
+}
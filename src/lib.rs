@@ -1,5 +1,18 @@
 pub mod broker;
+pub mod codec;
+pub mod connection;
+pub mod console;
 pub mod control;
+pub mod escape;
+pub mod forward;
+pub mod frame;
 pub mod message;
+pub mod protocol;
+pub mod reader;
+pub mod session;
+pub mod socks;
+pub mod transport;
 #[cfg(feature = "tty")]
 pub mod tty;
+pub mod version;
+pub mod writer;
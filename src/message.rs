@@ -1,15 +1,87 @@
 use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 
+use crate::version::Quirks;
+
+// sizeof(message->data_type) + sizeof(message->data_len)
+const MIN_HEADER_LEN: usize = 1 + 2;
+
+/// A frame that doesn't follow the wire format, naming the offending field.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// `header_len` too short for the fields the frame has to carry
+    HeaderLen(u16),
+    /// `data_type` not known to this implementation
+    DataType(u8),
+    /// `data_len` larger than the negotiated message data size
+    DataLen {
+        data_len: usize,
+        max_data_size: u16,
+    },
+    /// `header_origin` neither control nor target
+    HeaderOrigin(u16),
+    /// `header_type` not known for the data type
+    HeaderType(u16),
+    /// `header_proxy_type` not a known proxy type
+    HeaderProxyType(u16),
+    /// Winresize data not exactly rows and cols
+    WinresizeLen(usize),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::HeaderLen(header_len) => write!(f, "Bad header_len {}", header_len),
+            ProtocolError::DataType(data_type) => write!(f, "Unknown data_type {}", data_type),
+            ProtocolError::DataLen {
+                data_len,
+                max_data_size,
+            } => write!(
+                f,
+                "data_len {} over message data size {}",
+                data_len, max_data_size
+            ),
+            ProtocolError::HeaderOrigin(header_origin) => {
+                write!(f, "Unknown header_origin {}", header_origin)
+            }
+            ProtocolError::HeaderType(header_type) => {
+                write!(f, "Unknown header_type {}", header_type)
+            }
+            ProtocolError::HeaderProxyType(header_proxy_type) => {
+                write!(f, "Unknown header_proxy_type {}", header_proxy_type)
+            }
+            ProtocolError::WinresizeLen(len) => write!(f, "Bad winresize data of {} bytes", len),
+            ProtocolError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
 pub trait HeaderType {
     fn value(&self) -> u16;
 }
 
 #[repr(u8)]
-#[derive(Debug, PartialEq)]
-#[allow(unused)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ProxyType {
     Static = 0,
     Dynamic = 1,
@@ -28,6 +100,20 @@ impl HeaderType for ProxyType {
     }
 }
 
+impl TryFrom<u16> for ProxyType {
+    type Error = ProtocolError;
+
+    fn try_from(n: u16) -> Result<ProxyType, ProtocolError> {
+        match n {
+            0 => Ok(ProxyType::Static),
+            1 => Ok(ProxyType::Dynamic),
+            2 => Ok(ProxyType::Tun),
+            3 => Ok(ProxyType::Tap),
+            _ => Err(ProtocolError::HeaderProxyType(n)),
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, PartialEq)]
 pub enum ProxyHeaderType {
@@ -97,7 +183,35 @@ impl From<u16> for ConnectionHeaderType {
 }
 
 #[repr(u8)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum HeaderOrigin {
+    Control = 0,
+    Target = 1,
+    Unknown = 2,
+}
+
+impl HeaderOrigin {
+    pub fn value(&self) -> u16 {
+        match *self {
+            HeaderOrigin::Control => 0,
+            HeaderOrigin::Target => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl From<u16> for HeaderOrigin {
+    fn from(n: u16) -> HeaderOrigin {
+        match n {
+            0 => HeaderOrigin::Control,
+            1 => HeaderOrigin::Target,
+            _ => HeaderOrigin::Unknown,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum DataType {
     Init = 0,
     Tty = 1,
@@ -139,10 +253,10 @@ impl From<u8> for DataType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Message {
     pub data_type: DataType,
-    pub data: Vec<u8>,
+    pub data: Bytes,
     pub header_type: u16,
     pub header_origin: u16,
     pub header_id: u16,
@@ -153,7 +267,7 @@ impl Message {
     pub fn new() -> Self {
         Self {
             data_type: DataType::Unknown,
-            data: vec![],
+            data: Bytes::new(),
             header_type: 0,
             header_origin: 0,
             header_id: 0,
@@ -166,8 +280,8 @@ impl Message {
         self
     }
 
-    pub fn data(mut self, data: Vec<u8>) -> Self {
-        self.data = data;
+    pub fn data<T: Into<Bytes>>(mut self, data: T) -> Self {
+        self.data = data.into();
         self
     }
 
@@ -176,6 +290,11 @@ impl Message {
         self
     }
 
+    pub fn header_origin(mut self, header_origin: HeaderOrigin) -> Self {
+        self.header_origin = header_origin.value();
+        self
+    }
+
     pub fn header_id(mut self, header_id: u16) -> Self {
         self.header_id = header_id;
         self
@@ -193,57 +312,94 @@ impl Message {
         let stream = stream.clone();
         let mut stream = stream.lock().await;
         let stream = stream.as_mut().context("error")?;
+        self.write(stream).await
+    }
 
-        let mut header_len: u16 = (std::mem::size_of::<DataType>() + 2).try_into()?;
+    pub async fn write<T>(&self, stream: &mut T) -> Result<()>
+    where
+        T: AsyncWriteExt + std::marker::Unpin,
+    {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode(&mut buf)?;
+        stream.write_all(&buf).await?;
+        Ok(())
+    }
+
+    fn has_header_proxy_type(&self, quirks: Quirks) -> bool {
+        match self.data_type {
+            DataType::Proxy => matches!(
+                ProxyHeaderType::from(self.header_type),
+                ProxyHeaderType::Create | ProxyHeaderType::Report
+            ),
+            DataType::Connection => match ConnectionHeaderType::from(self.header_type) {
+                ConnectionHeaderType::Create => true,
+                ConnectionHeaderType::Data => quirks.connection_data_proxy_type,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn header_len(&self, quirks: Quirks) -> u16 {
+        // sizeof(message->data_type) + sizeof(message->data_len)
+        let mut header_len = 1 + 2;
 
         match self.data_type {
             DataType::Proxy | DataType::Connection => {
                 // sizeof(message->header_type) + sizeof(message->header_origin) + sizeof(message->header_id)
                 header_len += 3 * 2;
-                if ProxyHeaderType::from(self.header_type) == ProxyHeaderType::Create
-                    || ProxyHeaderType::from(self.header_type) == ProxyHeaderType::Report
-                    || ConnectionHeaderType::from(self.header_type) == ConnectionHeaderType::Create
-                {
+                if self.has_header_proxy_type(quirks) {
                     header_len += 2;
                 }
             }
             _ => {}
         }
+        header_len
+    }
+
+    /// Size of the whole frame on the wire.
+    pub fn encoded_len(&self) -> usize {
+        self.encoded_len_with(Quirks::default())
+    }
+
+    /// Size of the whole frame in the framing of `quirks`.
+    pub fn encoded_len_with(&self, quirks: Quirks) -> usize {
+        2 + usize::from(self.header_len(quirks)) + self.data.len()
+    }
+
+    /// Appends the frame to `buf` so it can go out in a single write.
+    pub fn encode<B: BufMut>(&self, buf: &mut B) -> Result<(), ProtocolError> {
+        self.encode_with(buf, Quirks::default())
+    }
+
+    /// Like `encode`, in the framing of the version `quirks` belong to.
+    pub fn encode_with<B: BufMut>(&self, buf: &mut B, quirks: Quirks) -> Result<(), ProtocolError> {
+        let data_len = u16::try_from(self.data.len()).map_err(|_| ProtocolError::DataLen {
+            data_len: self.data.len(),
+            max_data_size: u16::MAX,
+        })?;
 
-        stream.write_all(&u16::to_be_bytes(header_len)).await?;
-        stream
-            .write_all(&u8::to_be_bytes(self.data_type.value()))
-            .await?;
-        stream
-            .write_all(&u16::to_be_bytes(self.data.len().try_into()?))
-            .await?;
+        buf.put_u16(self.header_len(quirks));
+        buf.put_u8(self.data_type.value());
+        buf.put_u16(data_len);
 
         match self.data_type {
             DataType::Proxy | DataType::Connection => {
-                stream
-                    .write_all(&u16::to_be_bytes(self.header_type))
-                    .await?;
-                stream
-                    .write_all(&u16::to_be_bytes(self.header_origin))
-                    .await?;
-                stream.write_all(&u16::to_be_bytes(self.header_id)).await?;
-                if ProxyHeaderType::from(self.header_type) == ProxyHeaderType::Create
-                    || ProxyHeaderType::from(self.header_type) == ProxyHeaderType::Report
-                    || ConnectionHeaderType::from(self.header_type) == ConnectionHeaderType::Create
-                {
-                    stream
-                        .write_all(&u16::to_be_bytes(self.header_proxy_type))
-                        .await?;
+                buf.put_u16(self.header_type);
+                buf.put_u16(self.header_origin);
+                buf.put_u16(self.header_id);
+                if self.has_header_proxy_type(quirks) {
+                    buf.put_u16(self.header_proxy_type);
                 }
             }
             _ => {}
         }
 
-        stream.write_all(&self.data).await?;
+        buf.put_slice(&self.data);
         Ok(())
     }
 
-    pub async fn pull<T>(stream: &mut Arc<Mutex<Option<T>>>) -> Result<Self>
+    pub async fn pull<T>(stream: &mut Arc<Mutex<Option<T>>>, max_data_size: u16) -> Result<Self>
     where
         T: AsyncReadExt + std::marker::Unpin,
     {
@@ -251,62 +407,117 @@ impl Message {
         let mut stream = stream.lock().await;
         let stream = stream.as_mut().context("error")?;
 
-        let mut message = Self::new();
-
         let mut buf = [0u8; 2];
         stream.read_exact(&mut buf).await?;
-        let mut header_len = u16::from_be_bytes(buf);
+        let header_len = u16::from_be_bytes(buf);
+        if usize::from(header_len) < MIN_HEADER_LEN {
+            return Err(ProtocolError::HeaderLen(header_len).into());
+        }
 
-        let mut buf = [0u8; 1];
-        stream.read_exact(&mut buf).await?;
-        message.data_type = u8::from_be_bytes(buf).into();
-        header_len -= 1;
+        let mut header = vec![0u8; header_len.into()];
+        stream.read_exact(&mut header).await?;
+        let (mut message, data_len) =
+            Self::decode_header(&header, max_data_size, Quirks::default())?;
 
-        let mut buf = [0u8; 2];
-        stream.read_exact(&mut buf).await?;
-        let data_len = u16::from_be_bytes(buf);
-        header_len -= 2;
+        let mut data = vec![0u8; data_len.into()];
+        stream.read_exact(&mut data).await?;
+        message.data = data.into();
+
+        Ok(message)
+    }
+
+    /// Parses one frame from the front of `buf`. Returns `None` until the
+    /// whole frame is buffered. The payload is split off `buf` without a copy.
+    pub fn decode(buf: &mut BytesMut, max_data_size: u16) -> Result<Option<Self>, ProtocolError> {
+        Self::decode_with(buf, max_data_size, Quirks::default())
+    }
+
+    /// Like `decode`, in the framing of the version `quirks` belong to.
+    pub fn decode_with(
+        buf: &mut BytesMut,
+        max_data_size: u16,
+        quirks: Quirks,
+    ) -> Result<Option<Self>, ProtocolError> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let header_len = u16::from_be_bytes([buf[0], buf[1]]);
+        if usize::from(header_len) < MIN_HEADER_LEN {
+            return Err(ProtocolError::HeaderLen(header_len));
+        }
+        let header_end = 2 + usize::from(header_len);
+        if buf.len() < header_end {
+            buf.reserve(header_end - buf.len());
+            return Ok(None);
+        }
+
+        // Checked before waiting for the payload, so a bogus frame can't make
+        // us buffer more than the negotiated size
+        let (mut message, data_len) =
+            Self::decode_header(&buf[2..header_end], max_data_size, quirks)?;
+        let frame_len = header_end + usize::from(data_len);
+        if buf.len() < frame_len {
+            buf.reserve(frame_len - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(header_end);
+        message.data = buf.split_to(data_len.into()).freeze();
+        Ok(Some(message))
+    }
+
+    /// Parses the header following `header_len`. Returns the message without
+    /// its payload and the length of the payload.
+    fn decode_header(
+        mut header: &[u8],
+        max_data_size: u16,
+        quirks: Quirks,
+    ) -> Result<(Self, u16), ProtocolError> {
+        let header_len = header.len() as u16;
+        if header.remaining() < MIN_HEADER_LEN {
+            return Err(ProtocolError::HeaderLen(header_len));
+        }
+
+        let mut message = Self::new();
+        let data_type = header.get_u8();
+        message.data_type = data_type.into();
+        if message.data_type == DataType::Unknown {
+            return Err(ProtocolError::DataType(data_type));
+        }
+
+        let data_len = header.get_u16();
+        if data_len > max_data_size {
+            return Err(ProtocolError::DataLen {
+                data_len: data_len.into(),
+                max_data_size,
+            });
+        }
 
         match message.data_type {
             DataType::Proxy | DataType::Connection => {
-                let mut buf = [0u8; 2];
-                stream.read_exact(&mut buf).await?;
-                message.header_type = u16::from_be_bytes(buf);
-                header_len -= 2;
-
-                let mut buf = [0u8; 2];
-                stream.read_exact(&mut buf).await?;
-                message.header_origin = u16::from_be_bytes(buf);
-                header_len -= 2;
-
-                let mut buf = [0u8; 2];
-                stream.read_exact(&mut buf).await?;
-                message.header_id = u16::from_be_bytes(buf);
-                header_len -= 2;
-
-                if ProxyHeaderType::from(message.header_type) == ProxyHeaderType::Create
-                    || ProxyHeaderType::from(message.header_type) == ProxyHeaderType::Report
-                    || ConnectionHeaderType::from(message.header_type)
-                        == ConnectionHeaderType::Create
-                {
-                    let mut buf = [0u8; 2];
-                    stream.read_exact(&mut buf).await?;
-                    message.header_proxy_type = u16::from_be_bytes(buf);
-                    header_len -= 2;
+                // sizeof(message->header_type) + sizeof(message->header_origin) + sizeof(message->header_id)
+                if header.remaining() < 3 * 2 {
+                    return Err(ProtocolError::HeaderLen(header_len));
+                }
+                message.header_type = header.get_u16();
+                message.header_origin = header.get_u16();
+                if HeaderOrigin::from(message.header_origin) == HeaderOrigin::Unknown {
+                    return Err(ProtocolError::HeaderOrigin(message.header_origin));
+                }
+                message.header_id = header.get_u16();
+
+                if message.has_header_proxy_type(quirks) {
+                    if header.remaining() < 2 {
+                        return Err(ProtocolError::HeaderLen(header_len));
+                    }
+                    message.header_proxy_type = header.get_u16();
                 }
             }
             _ => {}
         }
 
-        if header_len > 0 {
-            let mut buf = vec![0u8; header_len.into()];
-            stream.read_exact(&mut buf).await?;
-        }
-
-        message.data = vec![0u8; data_len.into()];
-        stream.read_exact(&mut message.data).await?;
-
-        Ok(message)
+        // Anything left in the header is skipped, as the C revsh does
+        Ok((message, data_len))
     }
 }
 
@@ -0,0 +1,320 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::connection::ConnectionKey;
+use crate::frame::Frame;
+use crate::message::{HeaderOrigin, Message, ProtocolError};
+use crate::version::{ProtocolVersion, Quirks};
+
+// Smallest message data size the C revsh accepts
+const MIN_MESSAGE_DATA_SIZE: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Nothing sent yet
+    Start,
+    /// Waiting for the target's protocol major and minor
+    Version,
+    /// Waiting for the target's message data size
+    DataSize,
+    /// Waiting for the target to answer the interactive Init
+    Init,
+    Established,
+    /// The target broke the protocol, nothing more is accepted
+    Failed,
+}
+
+/// What the target told the control side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Handshake and Init sequence are done
+    Established {
+        version: ProtocolVersion,
+        message_data_size: u16,
+    },
+    Tty(Bytes),
+    Error(String),
+    /// The target accepted a connection on a remote forward
+    RemoteConnect {
+        key: ConnectionKey,
+        destination: String,
+    },
+    Data {
+        key: ConnectionKey,
+        data: Bytes,
+    },
+    /// The target is done sending on a connection
+    RemoteClosed(ConnectionKey),
+    /// The target can't keep up and asks for a pause
+    Paused(ConnectionKey),
+    /// The target resumed, which also means a connect went through
+    Resumed(ConnectionKey),
+}
+
+/// A protocol shared by the tasks reading from and writing to the target.
+pub type SharedProtocol = Arc<Mutex<ControlProtocol>>;
+
+/// The control side of the revsh protocol without any IO.
+///
+/// Bytes from the target go in through `receive`, or already decoded frames
+/// through `receive_frame`. Frames for the target go in through `send` once
+/// established. Bytes for the target come out of `poll_transmit` and
+/// whatever the target said comes out of `poll_event`.
+pub struct ControlProtocol {
+    state: State,
+    shell: String,
+    env: Vec<String>,
+    winsize: (u16, u16),
+    message_data_size: u16,
+    version: Option<ProtocolVersion>,
+    quirks: Quirks,
+    input: BytesMut,
+    output: BytesMut,
+    events: VecDeque<Event>,
+}
+
+impl ControlProtocol {
+    pub fn new() -> Self {
+        Self {
+            state: State::Start,
+            shell: "/bin/sh".to_string(),
+            env: vec![],
+            winsize: (0, 0),
+            message_data_size: u16::MAX,
+            version: None,
+            quirks: Quirks::default(),
+            input: BytesMut::new(),
+            output: BytesMut::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn shell(mut self, shell: String) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    pub fn env(mut self, env: Vec<String>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Terminal rows and cols sent with the Init sequence.
+    pub fn winsize(mut self, rows: u16, cols: u16) -> Self {
+        self.winsize = (rows, cols);
+        self
+    }
+
+    /// The message data size asked for, the target may lower it.
+    pub fn message_data_size(mut self, message_data_size: u16) -> Self {
+        self.message_data_size = message_data_size;
+        self
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The version the target announced, once known.
+    pub fn version(&self) -> Option<ProtocolVersion> {
+        self.version
+    }
+
+    /// Framing details of the announced version, or of the current one until
+    /// the target announced its own.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// The negotiated message data size once past the handshake.
+    pub fn negotiated_message_data_size(&self) -> u16 {
+        self.message_data_size
+    }
+
+    /// Queues the version announcement, the target sends its own at the
+    /// same time.
+    pub fn start(&mut self) {
+        if self.state != State::Start {
+            return;
+        }
+        self.output.put_u16(ProtocolVersion::CURRENT.major);
+        self.output.put_u16(ProtocolVersion::CURRENT.minor);
+        self.state = State::Version;
+    }
+
+    /// Bytes to write to the target, if any.
+    pub fn poll_transmit(&mut self) -> Option<Bytes> {
+        if self.output.is_empty() {
+            None
+        } else {
+            Some(self.output.split().freeze())
+        }
+    }
+
+    /// Queues a message for the target, only valid once established.
+    pub fn send<M: Into<Message>>(&mut self, message: M) -> Result<()> {
+        if self.state != State::Established {
+            bail!("Can't send in state {:?}", self.state);
+        }
+        let message = message.into();
+        if message.data.len() > usize::from(self.message_data_size) {
+            return Err(ProtocolError::DataLen {
+                data_len: message.data.len(),
+                max_data_size: self.message_data_size,
+            }
+            .into());
+        }
+        message.encode_with(&mut self.output, self.quirks)?;
+        Ok(())
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Hands over bytes received but not consumed yet, for a driver that
+    /// decodes frames itself once the handshake is done.
+    pub fn take_buffered(&mut self) -> BytesMut {
+        self.input.split()
+    }
+
+    /// Feeds bytes read from the target.
+    pub fn receive(&mut self, data: &[u8]) -> Result<()> {
+        self.input.extend_from_slice(data);
+        let result = self.process();
+        if result.is_err() {
+            self.state = State::Failed;
+        }
+        result
+    }
+
+    /// Feeds a frame decoded outside, only valid once past the version and
+    /// data size exchange.
+    pub fn receive_frame(&mut self, frame: Frame) -> Result<()> {
+        let result = match self.state {
+            State::Init | State::Established => self.handle_frame(frame),
+            state => Err(anyhow!("Unexpected frame in state {:?}", state)),
+        };
+        if result.is_err() {
+            self.state = State::Failed;
+        }
+        result
+    }
+
+    fn process(&mut self) -> Result<()> {
+        loop {
+            match self.state {
+                State::Start => bail!("Received data before start"),
+                State::Version => {
+                    if self.input.len() < 4 {
+                        return Ok(());
+                    }
+                    let proto_major = self.input.get_u16();
+                    let proto_minor = self.input.get_u16();
+                    debug!("Proto {}.{}", proto_major, proto_minor);
+                    let version = ProtocolVersion::new(proto_major, proto_minor).check()?;
+                    self.version = Some(version);
+                    self.quirks = version.quirks();
+
+                    // Send desired data size
+                    self.output.put_u16(self.message_data_size);
+                    self.state = State::DataSize;
+                }
+                State::DataSize => {
+                    if self.input.len() < 2 {
+                        return Ok(());
+                    }
+                    let data_size = self.input.get_u16();
+                    if data_size < MIN_MESSAGE_DATA_SIZE {
+                        bail!("Can't agree on a message size");
+                    }
+                    self.message_data_size = self.message_data_size.min(data_size);
+                    debug!("Data size {}", self.message_data_size);
+
+                    // Send interactive
+                    self.queue(Frame::Init(Bytes::from_static(&[0x1])))?;
+                    self.state = State::Init;
+                }
+                State::Init | State::Established => {
+                    match Message::decode_with(
+                        &mut self.input,
+                        self.message_data_size,
+                        self.quirks,
+                    )? {
+                        Some(message) => self.handle_frame(Frame::try_from(message)?)?,
+                        None => return Ok(()),
+                    }
+                }
+                State::Failed => bail!("Protocol failed earlier"),
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<()> {
+        if self.state == State::Init {
+            // The target's answer to the interactive Init isn't used
+            if !matches!(frame, Frame::Init(_)) {
+                bail!("Expected Init, got {:?}", frame);
+            }
+            let (rows, cols) = self.winsize;
+            let mut termios = Vec::with_capacity(4);
+            termios.extend(u16::to_be_bytes(rows));
+            termios.extend(u16::to_be_bytes(cols));
+
+            self.queue(Frame::Init(self.shell.clone().into()))?;
+            self.queue(Frame::Init(self.env.join(" ").into()))?;
+            self.queue(Frame::Init(termios.into()))?;
+            self.state = State::Established;
+            self.events.push_back(Event::Established {
+                version: self.version.unwrap_or_default(),
+                message_data_size: self.message_data_size,
+            });
+            return Ok(());
+        }
+
+        let event = match frame {
+            Frame::Tty(data) => Event::Tty(data),
+            Frame::Error(error) => Event::Error(error),
+            Frame::ConnectionCreate {
+                origin: HeaderOrigin::Target,
+                id,
+                destination,
+                ..
+            } => Event::RemoteConnect {
+                key: (HeaderOrigin::Target, id),
+                destination,
+            },
+            Frame::ConnectionDestroy { origin, id } => Event::RemoteClosed((origin, id)),
+            // Empty data is how the C revsh signals a closed connection
+            Frame::ConnectionData { origin, id, data } if data.is_empty() => {
+                Event::RemoteClosed((origin, id))
+            }
+            Frame::ConnectionData { origin, id, data } => Event::Data {
+                key: (origin, id),
+                data,
+            },
+            Frame::ConnectionDormant { origin, id } => Event::Paused((origin, id)),
+            Frame::ConnectionActive { origin, id } => Event::Resumed((origin, id)),
+            frame => {
+                debug!("Ignoring frame: {:?}", frame);
+                return Ok(());
+            }
+        };
+        self.events.push_back(event);
+        Ok(())
+    }
+
+    fn queue(&mut self, frame: Frame) -> Result<()> {
+        Message::from(frame).encode_with(&mut self.output, self.quirks)?;
+        Ok(())
+    }
+}
+
+impl Default for ControlProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,59 @@
+use bytes::BytesMut;
+use std::io;
+use tokio::io::AsyncReadExt;
+
+use crate::message::{Message, ProtocolError};
+use crate::version::Quirks;
+
+// Room made in the read buffer before each read
+const READ_BUFFER: usize = 64 * 1024;
+
+/// Reads frames from the target through one reusable buffer, so a read can
+/// pick up several frames at once and payloads are handed out without copies.
+pub struct MessageReader<T> {
+    stream: T,
+    buf: BytesMut,
+    max_data_size: u16,
+    quirks: Quirks,
+}
+
+impl<T> MessageReader<T>
+where
+    T: AsyncReadExt + std::marker::Unpin,
+{
+    pub fn new(stream: T, max_data_size: u16) -> Self {
+        Self {
+            stream,
+            buf: BytesMut::with_capacity(READ_BUFFER),
+            max_data_size,
+            quirks: Quirks::default(),
+        }
+    }
+
+    /// Starts from bytes already read off the stream, e.g. during the
+    /// handshake.
+    pub fn buffered(mut self, buf: BytesMut) -> Self {
+        self.buf = buf;
+        self
+    }
+
+    /// Reads frames the way the negotiated protocol version lays them out.
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub async fn read(&mut self) -> Result<Message, ProtocolError> {
+        loop {
+            if let Some(message) =
+                Message::decode_with(&mut self.buf, self.max_data_size, self.quirks)?
+            {
+                return Ok(message);
+            }
+            self.buf.reserve(READ_BUFFER);
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use log::info;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, DuplexStream};
+use tokio::sync::{mpsc, watch, Mutex};
+
+use crate::broker::Broker;
+use crate::transport::Transport;
+
+// Shell bytes buffered between a broker and the console in each direction
+const PIPE_SIZE: usize = 64 * 1024;
+// Output kept per session for replay on attach
+pub const SCROLLBACK_SIZE: usize = 256 * 1024;
+
+pub type SessionId = usize;
+
+/// An established target, running whether or not the console is attached.
+struct Session {
+    remote_address: String,
+    started: SystemTime,
+    /// Taken by the console while attached
+    input: Option<DuplexStream>,
+    output: Arc<Mutex<SessionOutput>>,
+    winsize: watch::Sender<(u16, u16)>,
+}
+
+/// Where the shell output of a session goes.
+struct SessionOutput {
+    scrollback: Scrollback,
+    attached: Option<mpsc::UnboundedSender<Bytes>>,
+}
+
+/// The last output of a session, bounded to `limit` bytes.
+struct Scrollback {
+    data: BytesMut,
+    limit: usize,
+}
+
+impl Scrollback {
+    fn new(limit: usize) -> Self {
+        Self {
+            data: BytesMut::new(),
+            limit,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+        if self.data.len() > self.limit {
+            let excess = self.data.len() - self.limit;
+            self.data.advance(excess);
+        }
+    }
+}
+
+/// The console's ends of an attached session's shell, handed back with
+/// `SessionTable::detach`.
+pub struct SessionIo {
+    pub id: SessionId,
+    /// Goes to the target as Tty data
+    pub input: DuplexStream,
+    /// Output from before the attach, to replay first
+    pub scrollback: Bytes,
+    /// Tty data from the target, closed once the session ended
+    pub output: mpsc::UnboundedReceiver<Bytes>,
+}
+
+/// A row of the session list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub remote_address: String,
+    pub started: SystemTime,
+    pub attached: bool,
+}
+
+impl SessionInfo {
+    /// How long the session has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed().unwrap_or_default()
+    }
+}
+
+/// All sessions the control is running, shared between the accept loop and
+/// the console.
+#[derive(Clone)]
+pub struct SessionTable {
+    sessions: Arc<Mutex<BTreeMap<SessionId, Session>>>,
+    next_id: Arc<AtomicUsize>,
+    scrollback_size: usize,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(BTreeMap::new())),
+            next_id: Arc::new(AtomicUsize::new(1)),
+            scrollback_size: SCROLLBACK_SIZE,
+        }
+    }
+
+    /// Bytes of output kept per session while detached.
+    pub fn scrollback_size(mut self, scrollback_size: usize) -> Self {
+        self.scrollback_size = scrollback_size;
+        self
+    }
+
+    /// Runs `broker` until the target goes away and returns the id it is
+    /// listed under.
+    pub async fn insert<T: Transport>(&self, mut broker: Broker<T>) -> SessionId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (input, broker_input) = tokio::io::duplex(PIPE_SIZE);
+        let (broker_output, output) = tokio::io::duplex(PIPE_SIZE);
+        let (winsize, broker_winsize) = watch::channel((0, 0));
+        broker
+            .input(broker_input)
+            .output(broker_output)
+            .winsize(broker_winsize);
+
+        let session_output = Arc::new(Mutex::new(SessionOutput {
+            scrollback: Scrollback::new(self.scrollback_size),
+            attached: None,
+        }));
+        self.sessions.lock().await.insert(
+            id,
+            Session {
+                remote_address: broker.remote_address.clone(),
+                started: SystemTime::now(),
+                input: Some(input),
+                output: session_output.clone(),
+                winsize,
+            },
+        );
+
+        tokio::spawn(Self::output_handler(output, session_output));
+        let sessions = self.sessions.clone();
+        tokio::spawn(async move {
+            if let Err(e) = broker.run().await {
+                info!("Session {} failed: {}", id, e);
+            }
+            sessions.lock().await.remove(&id);
+            info!("Session {} closed", id);
+        });
+        id
+    }
+
+    /// Keeps reading the shell output of a session so the target is never
+    /// held up by a detached session.
+    async fn output_handler(
+        mut output: DuplexStream,
+        session_output: Arc<Mutex<SessionOutput>>,
+    ) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(PIPE_SIZE);
+        loop {
+            buf.reserve(PIPE_SIZE);
+            if output.read_buf(&mut buf).await? == 0 {
+                break;
+            }
+            let data = buf.split().freeze();
+            let mut session_output = session_output.lock().await;
+            session_output.scrollback.push(&data);
+            let attached_gone = match &session_output.attached {
+                Some(attached) => attached.send(data).is_err(),
+                None => false,
+            };
+            if attached_gone {
+                session_output.attached = None;
+            }
+        }
+        // Closing the channel tells an attached console the session ended
+        session_output.lock().await.attached = None;
+        Ok(())
+    }
+
+    /// The running sessions ordered by id.
+    pub async fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(id, session)| SessionInfo {
+                id: *id,
+                remote_address: session.remote_address.clone(),
+                started: session.started,
+                attached: session.input.is_none(),
+            })
+            .collect()
+    }
+
+    /// Takes the shell of session `id` for the console.
+    pub async fn attach(&self, id: SessionId) -> Result<SessionIo> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or_else(|| anyhow!("No session {}", id))?;
+        let input = match session.input.take() {
+            Some(input) => input,
+            None => bail!("Session {} is already attached", id),
+        };
+
+        let (sender, output) = mpsc::unbounded_channel();
+        let mut session_output = session.output.lock().await;
+        session_output.attached = Some(sender);
+        Ok(SessionIo {
+            id,
+            input,
+            scrollback: Bytes::copy_from_slice(&session_output.scrollback.data),
+            output,
+        })
+    }
+
+    /// Puts an attached session back in the background, its output goes to
+    /// the scrollback only.
+    pub async fn detach(&self, io: SessionIo) {
+        if let Some(session) = self.sessions.lock().await.get_mut(&io.id) {
+            session.output.lock().await.attached = None;
+            session.input = Some(io.input);
+        }
+    }
+
+    /// Tells the target of session `id` the terminal size changed.
+    pub async fn resize(&self, id: SessionId, rows: u16, cols: u16) {
+        if let Some(session) = self.sessions.lock().await.get(&id) {
+            let _ = session.winsize.send((rows, cols));
+        }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.sessions.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.sessions.lock().await.is_empty()
+    }
+}
+
+impl Default for SessionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{bail, Result};
+use log::debug;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const SOCKS4_VERSION: u8 = 4;
+const SOCKS5_VERSION: u8 = 5;
+
+const COMMAND_CONNECT: u8 = 1;
+
+// Upper bound for the NUL-terminated SOCKS4 USERID and SOCKS4a hostname
+const MAX_SOCKS4_FIELD_LEN: usize = 255;
+
+const SOCKS4_GRANTED: u8 = 90;
+const SOCKS4_REJECTED: u8 = 91;
+
+const SOCKS5_METHOD_NO_AUTH: u8 = 0x00;
+const SOCKS5_METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const SOCKS5_ATYP_IPV4: u8 = 1;
+const SOCKS5_ATYP_DOMAIN: u8 = 3;
+const SOCKS5_ATYP_IPV6: u8 = 4;
+
+const SOCKS5_SUCCEEDED: u8 = 0;
+const SOCKS5_HOST_UNREACHABLE: u8 = 4;
+const SOCKS5_CONNECTION_REFUSED: u8 = 5;
+const SOCKS5_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS5_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocksVersion {
+    V4,
+    V5,
+}
+
+/// Outcome of a connection create, as reported back to the SOCKS client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectResult {
+    Granted,
+    Refused,
+    TimedOut,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SocksAddress {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Domain(String),
+}
+
+impl fmt::Display for SocksAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocksAddress::Ipv4(ip) => write!(f, "{}", ip),
+            SocksAddress::Ipv6(ip) => write!(f, "[{}]", ip),
+            SocksAddress::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SocksRequest {
+    pub version: SocksVersion,
+    pub address: SocksAddress,
+    pub port: u16,
+}
+
+impl SocksRequest {
+    /// Reads a CONNECT request from a SOCKS client, doing the SOCKS 5 method
+    /// negotiation first when needed.
+    pub async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin,
+    {
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).await?;
+        match u8::from_be_bytes(buf) {
+            SOCKS4_VERSION => Self::read_socks4(stream).await,
+            SOCKS5_VERSION => Self::read_socks5(stream).await,
+            _ => bail!("Wrong socks version"),
+        }
+    }
+
+    async fn read_socks4<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + std::marker::Unpin,
+    {
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).await?;
+        let command = u8::from_be_bytes(buf);
+        if command != COMMAND_CONNECT {
+            bail!("Wrong command");
+        }
+
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await?;
+        let port = u16::from_be_bytes(buf);
+
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        let ip = Ipv4Addr::from(buf);
+
+        let user_id = Self::read_nul_terminated(stream).await?;
+        debug!("Socks4 user id {:?}", String::from_utf8_lossy(&user_id));
+
+        // SOCKS4a: 0.0.0.x with x != 0 means a hostname follows the user id
+        let address = match buf {
+            [0, 0, 0, x] if x != 0 => {
+                let domain = Self::read_nul_terminated(stream).await?;
+                SocksAddress::Domain(String::from_utf8(domain)?)
+            }
+            _ => SocksAddress::Ipv4(ip),
+        };
+
+        Ok(Self {
+            version: SocksVersion::V4,
+            address,
+            port,
+        })
+    }
+
+    async fn read_nul_terminated<T>(stream: &mut T) -> Result<Vec<u8>>
+    where
+        T: AsyncReadExt + std::marker::Unpin,
+    {
+        let mut data = Vec::new();
+        loop {
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf).await?;
+            if buf[0] == 0 {
+                return Ok(data);
+            }
+            if data.len() == MAX_SOCKS4_FIELD_LEN {
+                bail!("Socks4 field too long");
+            }
+            data.push(buf[0]);
+        }
+    }
+
+    async fn read_socks5<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin,
+    {
+        // Method negotiation
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).await?;
+        let mut methods = vec![0u8; u8::from_be_bytes(buf).into()];
+        stream.read_exact(&mut methods).await?;
+
+        if !methods.contains(&SOCKS5_METHOD_NO_AUTH) {
+            stream
+                .write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_NONE_ACCEPTABLE])
+                .await?;
+            bail!("No acceptable socks authentication method");
+        }
+        stream
+            .write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_NO_AUTH])
+            .await?;
+
+        // Request
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        let [version, command, _reserved, address_type] = buf;
+        if version != SOCKS5_VERSION {
+            bail!("Wrong socks version");
+        }
+        if command != COMMAND_CONNECT {
+            Self::write_socks5_reply(stream, SOCKS5_COMMAND_NOT_SUPPORTED).await?;
+            bail!("Wrong command");
+        }
+
+        let address = match address_type {
+            SOCKS5_ATYP_IPV4 => {
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await?;
+                SocksAddress::Ipv4(Ipv4Addr::from(buf))
+            }
+            SOCKS5_ATYP_IPV6 => {
+                let mut buf = [0u8; 16];
+                stream.read_exact(&mut buf).await?;
+                SocksAddress::Ipv6(Ipv6Addr::from(buf))
+            }
+            SOCKS5_ATYP_DOMAIN => {
+                let mut buf = [0u8; 1];
+                stream.read_exact(&mut buf).await?;
+                let mut domain = vec![0u8; u8::from_be_bytes(buf).into()];
+                stream.read_exact(&mut domain).await?;
+                SocksAddress::Domain(String::from_utf8(domain)?)
+            }
+            _ => {
+                Self::write_socks5_reply(stream, SOCKS5_ADDRESS_NOT_SUPPORTED).await?;
+                bail!("Wrong address type");
+            }
+        };
+
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await?;
+        let port = u16::from_be_bytes(buf);
+
+        Ok(Self {
+            version: SocksVersion::V5,
+            address,
+            port,
+        })
+    }
+
+    /// The "host:port" string sent to the target. Domain names are passed
+    /// unresolved so the lookup happens on the target side.
+    pub fn connection_string(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+
+    /// The reply telling the SOCKS client how the connect on the target went.
+    pub fn reply(&self, result: ConnectResult) -> Vec<u8> {
+        match self.version {
+            SocksVersion::V4 => {
+                let ip = match self.address {
+                    SocksAddress::Ipv4(ip) => ip,
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                let status = match result {
+                    ConnectResult::Granted => SOCKS4_GRANTED,
+                    ConnectResult::Refused | ConnectResult::TimedOut => SOCKS4_REJECTED,
+                };
+                let mut data = vec![0, status];
+                data.extend(u16::to_be_bytes(self.port));
+                data.extend(ip.octets());
+                data
+            }
+            SocksVersion::V5 => Self::socks5_reply(match result {
+                ConnectResult::Granted => SOCKS5_SUCCEEDED,
+                ConnectResult::Refused => SOCKS5_CONNECTION_REFUSED,
+                ConnectResult::TimedOut => SOCKS5_HOST_UNREACHABLE,
+            }),
+        }
+    }
+
+    async fn write_socks5_reply<T>(stream: &mut T, reply: u8) -> Result<()>
+    where
+        T: AsyncWriteExt + std::marker::Unpin,
+    {
+        stream.write_all(&Self::socks5_reply(reply)).await?;
+        Ok(())
+    }
+
+    fn socks5_reply(reply: u8) -> Vec<u8> {
+        // The bound address is not known on this side, report 0.0.0.0:0
+        let mut data = vec![SOCKS5_VERSION, reply, 0, SOCKS5_ATYP_IPV4];
+        data.extend(Ipv4Addr::UNSPECIFIED.octets());
+        data.extend(u16::to_be_bytes(0));
+        data
+    }
+}
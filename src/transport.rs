@@ -0,0 +1,104 @@
+use anyhow::Result;
+use std::fs::File;
+use std::future::Future;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_native_tls::native_tls::TlsAcceptor as NativeTlsAcceptor;
+use tokio_native_tls::native_tls::{Identity, Protocol};
+use tokio_native_tls::TlsAcceptor as TokioTlsAcceptor;
+use tokio_native_tls::TlsStream;
+
+/// Anything a session can run over.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+type Handshake<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// A target that connected but may still have to finish a handshake of its
+/// own, like TLS, before it is a `Transport`.
+pub struct Incoming<T> {
+    pub remote_address: String,
+    handshake: Handshake<T>,
+}
+
+impl<T> Incoming<T> {
+    pub fn new<F>(remote_address: String, handshake: F) -> Self
+    where
+        F: Future<Output = Result<T>> + Send + 'static,
+    {
+        Self {
+            remote_address,
+            handshake: Box::pin(handshake),
+        }
+    }
+
+    /// A stream that is ready as it is.
+    pub fn ready(remote_address: String, stream: T) -> Self
+    where
+        T: Send + 'static,
+    {
+        Self::new(remote_address, async { Ok(stream) })
+    }
+
+    /// Finishes the handshake, so a slow target only holds up its own task.
+    pub async fn stream(self) -> Result<T> {
+        self.handshake.await
+    }
+}
+
+/// Hands `Control` new targets.
+pub trait Acceptor: Send + 'static {
+    type Stream: Transport;
+
+    /// Waits for the next target. Only the accept itself happens here, the
+    /// handshake is left to `Incoming::stream`.
+    fn accept(&mut self) -> impl Future<Output = Result<Incoming<Self::Stream>>> + Send;
+}
+
+/// TLS over TCP, what the C revsh target speaks.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TokioTlsAcceptor,
+}
+
+impl TlsListener {
+    pub async fn bind(address: SocketAddr, key_file: &Path) -> Result<Self> {
+        let listener: TcpListener = TcpListener::bind(&address).await?;
+
+        // openssl pkcs12 -export -out identity.pfx -inkey key.pem -in cert.pem
+        let mut file = File::open(key_file)?;
+        let mut identity = vec![];
+        file.read_to_end(&mut identity)?;
+        let identity = Identity::from_pkcs12(&identity, "")?;
+
+        let acceptor = NativeTlsAcceptor::builder(identity)
+            .min_protocol_version(Some(Protocol::Sslv3))
+            .build()?;
+
+        Ok(Self {
+            listener,
+            acceptor: TokioTlsAcceptor::from(acceptor),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+impl Acceptor for TlsListener {
+    type Stream = TlsStream<TcpStream>;
+
+    async fn accept(&mut self) -> Result<Incoming<Self::Stream>> {
+        let (stream, remote_address) = self.listener.accept().await?;
+        let acceptor = self.acceptor.clone();
+        Ok(Incoming::new(remote_address.to_string(), async move {
+            Ok(acceptor.accept(stream).await?)
+        }))
+    }
+}
@@ -1,7 +1,11 @@
+use anyhow::Result;
 use log::debug;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 
-pub static UPDATE_WINSIZE: AtomicBool = AtomicBool::new(false);
+// Shortest time between two window size updates while resizing
+const WINSIZE_DEBOUNCE: Duration = Duration::from_millis(50);
 
 pub struct Tty {
     saved_termios: libc::termios,
@@ -11,12 +15,9 @@ impl Tty {
     pub fn new() -> Self {
         unsafe {
             debug!("Set up TTY");
-            let mut act: libc::sigaction = std::mem::zeroed();
-            act.sa_sigaction = Self::signal_handler as libc::sighandler_t;
-            libc::sigaction(libc::SIGWINCH, &act, std::ptr::null_mut());
             let mut saved_termios: libc::termios = std::mem::zeroed();
             libc::tcgetattr(libc::STDIN_FILENO, &mut saved_termios);
-            let mut revsh_termios = saved_termios.clone();
+            let mut revsh_termios = saved_termios;
 
             libc::cfmakeraw(&mut revsh_termios);
             libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &revsh_termios);
@@ -38,10 +39,27 @@ impl Tty {
         (term_width, term_height)
     }
 
-    fn signal_handler(signal: i32) {
-        if signal == libc::SIGWINCH {
-            UPDATE_WINSIZE.store(true, Ordering::Relaxed);
-        }
+    /// Follows the terminal size from SIGWINCH in its own task, so a resize
+    /// goes out without waiting for any traffic. The first signal of a burst
+    /// is passed on right away, the rest at most every `WINSIZE_DEBOUNCE`.
+    pub fn watch_winsize() -> Result<watch::Receiver<(u16, u16)>> {
+        let mut signals = signal(SignalKind::window_change())?;
+        let mut winsize = Tty::get_term_size();
+        let (sender, receiver) = watch::channel(winsize);
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                let new_winsize = Tty::get_term_size();
+                if new_winsize != winsize {
+                    debug!("Window size {:?}", new_winsize);
+                    winsize = new_winsize;
+                    if sender.send(winsize).is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(WINSIZE_DEBOUNCE).await;
+            }
+        });
+        Ok(receiver)
     }
 }
 
@@ -49,7 +67,6 @@ impl Drop for Tty {
     fn drop(&mut self) {
         unsafe {
             debug!("Leaving of TTY");
-            libc::signal(libc::SIGWINCH, libc::SIG_DFL);
             libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved_termios);
         }
     }
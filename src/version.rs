@@ -0,0 +1,104 @@
+use anyhow::{bail, Result};
+use log::warn;
+use std::fmt;
+
+/// A protocol major this control can talk to.
+struct Compatibility {
+    major: u16,
+    /// Newest minor known, newer ones are accepted as minors are meant to
+    /// stay compatible
+    minor: u16,
+    quirks: Quirks,
+}
+
+/// Every major not listed here fails the handshake.
+const COMPATIBILITY: &[Compatibility] = &[Compatibility {
+    major: 1,
+    minor: 0,
+    quirks: Quirks {
+        connection_data_proxy_type: true,
+    },
+}];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+/// Wire details that differ between protocol versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// Connection Data carries `header_proxy_type`, as the C revsh checks the
+    /// raw header_type against Proxy Report (2) too
+    pub connection_data_proxy_type: bool,
+}
+
+/// The framing of the version this control announces.
+impl Default for Quirks {
+    fn default() -> Self {
+        ProtocolVersion::CURRENT.quirks()
+    }
+}
+
+impl ProtocolVersion {
+    /// The version this control announces.
+    pub const CURRENT: Self = Self { major: 1, minor: 0 };
+
+    pub fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+
+    /// Checks the version a target announced against the compatibility
+    /// matrix.
+    pub fn check(self) -> Result<Self> {
+        match Self::compatibility(self.major) {
+            Some(compatibility) => {
+                if self.minor > compatibility.minor {
+                    warn!(
+                        "Target protocol {} is newer than {}.{}",
+                        self, compatibility.major, compatibility.minor
+                    );
+                }
+                Ok(self)
+            }
+            None => bail!(
+                "Target speaks protocol {}, control supports {}",
+                self,
+                Self::supported()
+            ),
+        }
+    }
+
+    /// Behaviour that depends on the negotiated version.
+    pub fn quirks(&self) -> Quirks {
+        Self::compatibility(self.major)
+            .or_else(|| Self::compatibility(Self::CURRENT.major))
+            .map(|compatibility| compatibility.quirks)
+            .expect("current version in compatibility matrix")
+    }
+
+    fn compatibility(major: u16) -> Option<&'static Compatibility> {
+        COMPATIBILITY.iter().find(|c| c.major == major)
+    }
+
+    fn supported() -> String {
+        COMPATIBILITY
+            .iter()
+            .map(|c| format!("{}.x", c.major))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::CURRENT
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
@@ -0,0 +1,353 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::connection::ConnectionKey;
+use crate::frame::Frame;
+use crate::message::{
+    ConnectionHeaderType, DataType, HeaderOrigin, Message, ProtocolError, ProxyType,
+};
+use crate::protocol::SharedProtocol;
+use crate::version::Quirks;
+
+// Connection messages buffered by the writer task, also the bound of the
+// channel feeding it so fast local readers get backpressure.
+const DATA_BUFFER: usize = 64;
+
+// Frames are packed into one write up to about the size of a TLS record
+const BATCH_SIZE: usize = 16 * 1024;
+
+/// Cloneable handle queueing messages for the single task that writes to the
+/// target.
+///
+/// Tty, Winresize, Proxy and connection Destroy, Dormant and Active messages
+/// go out before any connection data, a Destroy only waits for the data of
+/// its own connection. Create, Data and Destroy of a connection keep their
+/// order and connections take turns so a bulk transfer can't starve the
+/// others.
+///
+/// Tty and connection data larger than the negotiated message data size is
+/// split over several messages.
+#[derive(Clone)]
+pub struct MessageWriter {
+    control: mpsc::UnboundedSender<Message>,
+    data: mpsc::Sender<Message>,
+    message_data_size: u16,
+}
+
+pub struct WriterTask<T> {
+    stream: T,
+    control: mpsc::UnboundedReceiver<Message>,
+    data: mpsc::Receiver<Message>,
+    quirks: Quirks,
+    protocol: Option<SharedProtocol>,
+}
+
+impl MessageWriter {
+    /// Creates the handle and the task to be spawned for `stream`.
+    pub fn new<T>(stream: T, message_data_size: u16) -> (Self, WriterTask<T>)
+    where
+        T: AsyncWriteExt + std::marker::Unpin,
+    {
+        let (control, control_receiver) = mpsc::unbounded_channel();
+        let (data, data_receiver) = mpsc::channel(DATA_BUFFER);
+        (
+            Self {
+                control,
+                data,
+                message_data_size,
+            },
+            WriterTask {
+                stream,
+                control: control_receiver,
+                data: data_receiver,
+                quirks: Quirks::default(),
+                protocol: None,
+            },
+        )
+    }
+
+    /// The largest payload a single message may carry.
+    pub fn message_data_size(&self) -> u16 {
+        self.message_data_size
+    }
+
+    pub async fn send<M: Into<Message>>(&self, message: M) -> Result<()> {
+        let mut message = message.into();
+        let max = usize::from(self.message_data_size);
+        if message.data.len() > max {
+            if !Self::is_stream_data(&message) {
+                return Err(ProtocolError::DataLen {
+                    data_len: message.data.len(),
+                    max_data_size: self.message_data_size,
+                }
+                .into());
+            }
+            while message.data.len() > max {
+                let chunk = Message {
+                    data: message.data.split_to(max),
+                    ..message.clone()
+                };
+                self.queue(chunk).await?;
+            }
+        }
+        self.queue(message).await
+    }
+
+    pub async fn proxy_create(&self, id: u16, spec: &str) -> Result<()> {
+        self.send(Frame::ProxyCreate {
+            origin: HeaderOrigin::Control,
+            id,
+            proxy_type: ProxyType::Static,
+            spec: spec.to_string(),
+        })
+        .await
+    }
+
+    pub async fn proxy_destroy(&self, id: u16) -> Result<()> {
+        self.send(Frame::ProxyDestroy {
+            origin: HeaderOrigin::Control,
+            id,
+        })
+        .await
+    }
+
+    pub async fn connection_create(
+        &self,
+        id: u16,
+        proxy_type: ProxyType,
+        destination: &str,
+    ) -> Result<()> {
+        self.send(Frame::ConnectionCreate {
+            origin: HeaderOrigin::Control,
+            id,
+            proxy_type,
+            destination: destination.to_string(),
+        })
+        .await
+    }
+
+    pub async fn connection_destroy(&self, key: ConnectionKey) -> Result<()> {
+        self.send(Frame::ConnectionDestroy {
+            origin: key.0,
+            id: key.1,
+        })
+        .await
+    }
+
+    pub async fn connection_dormant(&self, key: ConnectionKey) -> Result<()> {
+        self.send(Frame::ConnectionDormant {
+            origin: key.0,
+            id: key.1,
+        })
+        .await
+    }
+
+    pub async fn connection_active(&self, key: ConnectionKey) -> Result<()> {
+        self.send(Frame::ConnectionActive {
+            origin: key.0,
+            id: key.1,
+        })
+        .await
+    }
+
+    pub async fn connection_data(&self, key: ConnectionKey, data: Bytes) -> Result<()> {
+        self.send(Frame::ConnectionData {
+            origin: key.0,
+            id: key.1,
+            data,
+        })
+        .await
+    }
+
+    async fn queue(&self, message: Message) -> Result<()> {
+        // Only Create and Data wait for room, closing a connection or
+        // pausing the target mustn't be held up by a busy one
+        if Self::is_backpressured(&message) {
+            self.data
+                .send(message)
+                .await
+                .map_err(|_| anyhow!("Writer gone"))
+        } else {
+            self.control
+                .send(message)
+                .map_err(|_| anyhow!("Writer gone"))
+        }
+    }
+
+    /// Tty and connection data are byte streams that can be cut anywhere.
+    fn is_stream_data(message: &Message) -> bool {
+        match message.data_type {
+            DataType::Tty => true,
+            DataType::Connection => {
+                ConnectionHeaderType::from(message.header_type) == ConnectionHeaderType::Data
+            }
+            _ => false,
+        }
+    }
+
+    fn is_backpressured(message: &Message) -> bool {
+        message.data_type == DataType::Connection
+            && matches!(
+                ConnectionHeaderType::from(message.header_type),
+                ConnectionHeaderType::Create | ConnectionHeaderType::Data
+            )
+    }
+
+    /// The connection a message has to stay in order with, if any.
+    fn connection_key(message: &Message) -> Option<ConnectionKey> {
+        if message.data_type != DataType::Connection {
+            return None;
+        }
+        match ConnectionHeaderType::from(message.header_type) {
+            ConnectionHeaderType::Create
+            | ConnectionHeaderType::Data
+            | ConnectionHeaderType::Destroy => {
+                Some((HeaderOrigin::from(message.header_origin), message.header_id))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Messages waiting in the writer task, in the order they should go out.
+#[derive(Default)]
+struct Queues {
+    control: VecDeque<Message>,
+    connections: HashMap<ConnectionKey, VecDeque<Message>>,
+    turns: VecDeque<ConnectionKey>,
+    buffered: usize,
+}
+
+impl Queues {
+    /// A Destroy goes behind the data its connection still has queued.
+    fn push_control(&mut self, message: Message) {
+        if let Some(key) = MessageWriter::connection_key(&message) {
+            if let Some(queue) = self.connections.get_mut(&key) {
+                queue.push_back(message);
+                self.buffered += 1;
+                return;
+            }
+        }
+        self.control.push_back(message);
+    }
+
+    fn push_data(&mut self, message: Message) {
+        let key = MessageWriter::connection_key(&message).expect("connection message");
+        let queue = self.connections.entry(key).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(key);
+        }
+        queue.push_back(message);
+        self.buffered += 1;
+    }
+
+    /// Control messages first, then one message from the next connection.
+    fn pop(&mut self) -> Option<Message> {
+        if let Some(message) = self.control.pop_front() {
+            return Some(message);
+        }
+        let key = self.turns.pop_front()?;
+        let queue = self.connections.get_mut(&key).expect("queued connection");
+        let message = queue.pop_front();
+        if queue.is_empty() {
+            self.connections.remove(&key);
+        } else {
+            self.turns.push_back(key);
+        }
+        self.buffered -= 1;
+        message
+    }
+}
+
+impl<T> WriterTask<T>
+where
+    T: AsyncWriteExt + std::marker::Unpin,
+{
+    /// Writes frames the way the negotiated protocol version lays them out.
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Sends messages through the established `protocol` instead of framing
+    /// them here.
+    pub fn protocol(mut self, protocol: SharedProtocol) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Writes messages until every `MessageWriter` is dropped and everything
+    /// queued is out. Queued frames are packed into one buffer so a burst
+    /// becomes a single TLS write.
+    pub async fn run(mut self) -> Result<()> {
+        let mut queues = Queues::default();
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+        loop {
+            while batch.len() < BATCH_SIZE {
+                self.receive_ready(&mut queues);
+                match queues.pop() {
+                    Some(message) => self.encode(message, &mut batch)?,
+                    None => break,
+                }
+            }
+
+            if !batch.is_empty() {
+                self.stream.write_all(&batch).await?;
+                batch.clear();
+                continue;
+            }
+
+            // Nothing left, wait for more
+            self.stream.flush().await?;
+            tokio::select! {
+                biased;
+                Some(message) = self.control.recv() => self.push_control(&mut queues, message),
+                Some(message) = self.data.recv() => queues.push_data(message),
+                else => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn encode(&self, message: Message, batch: &mut Vec<u8>) -> Result<()> {
+        match &self.protocol {
+            Some(protocol) => {
+                let mut protocol = protocol.lock().expect("protocol lock");
+                protocol.send(message)?;
+                while let Some(data) = protocol.poll_transmit() {
+                    batch.extend_from_slice(&data);
+                }
+            }
+            None => message.encode_with(batch, self.quirks)?,
+        }
+        Ok(())
+    }
+
+    /// Data sent before a Destroy may still be in the channel, it is taken in
+    /// first so the Destroy can't overtake it.
+    fn push_control(&mut self, queues: &mut Queues, message: Message) {
+        if MessageWriter::connection_key(&message).is_some() {
+            while let Ok(data) = self.data.try_recv() {
+                queues.push_data(data);
+            }
+        }
+        queues.push_control(message);
+    }
+
+    /// Takes whatever is ready without waiting.
+    fn receive_ready(&mut self, queues: &mut Queues) {
+        while let Ok(message) = self.control.try_recv() {
+            self.push_control(queues, message);
+        }
+        while queues.buffered < DATA_BUFFER {
+            match self.data.try_recv() {
+                Ok(message) => queues.push_data(message),
+                Err(_) => break,
+            }
+        }
+    }
+}
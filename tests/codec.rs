@@ -0,0 +1,121 @@
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use revsh::codec::RevshCodec;
+use revsh::message::{
+    ConnectionHeaderType, DataType, HeaderOrigin, Message, ProtocolError, ProxyHeaderType,
+    ProxyType,
+};
+
+fn messages() -> Vec<Message> {
+    vec![
+        Message::new().data_type(DataType::Tty).data(&b"id\n"[..]),
+        Message::new()
+            .data_type(DataType::Proxy)
+            .header_type(ProxyHeaderType::Create)
+            .header_proxy_type(ProxyType::Dynamic)
+            .data(&b"1080:127.0.0.1:1081"[..]),
+        Message::new()
+            .data_type(DataType::Connection)
+            .header_type(ConnectionHeaderType::Data)
+            .header_origin(HeaderOrigin::Target)
+            .header_id(7)
+            .data(vec![0x41; 4096]),
+        Message::new()
+            .data_type(DataType::Connection)
+            .header_type(ConnectionHeaderType::Destroy)
+            .header_origin(HeaderOrigin::Control)
+            .header_id(7),
+    ]
+}
+
+#[tokio::test]
+async fn framed_round_trip() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = Framed::new(client, RevshCodec::new());
+    let mut server = Framed::new(server, RevshCodec::new());
+
+    let sender = tokio::spawn(async move {
+        for message in messages() {
+            client.send(message).await.unwrap();
+        }
+    });
+    for expected in messages() {
+        let message = server.next().await.unwrap().unwrap();
+        assert_eq!(message, expected);
+    }
+    sender.await.unwrap();
+}
+
+#[test]
+fn decode_waits_for_whole_frame() {
+    let mut frame = Vec::new();
+    messages()[2].encode(&mut frame).unwrap();
+
+    let mut codec = RevshCodec::new();
+    let mut buf = BytesMut::new();
+    for byte in &frame[..frame.len() - 1] {
+        buf.extend_from_slice(&[*byte]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+    buf.extend_from_slice(&frame[frame.len() - 1..]);
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), messages()[2]);
+    assert!(buf.is_empty());
+}
+
+fn decode_error(frame: &[u8]) -> ProtocolError {
+    let mut buf = BytesMut::from(frame);
+    RevshCodec::new()
+        .max_data_size(1024)
+        .decode(&mut buf)
+        .unwrap_err()
+}
+
+#[test]
+fn malformed_frames_name_the_field() {
+    // header_len that can't even hold data_type and data_len
+    assert!(matches!(
+        decode_error(&[0, 1, 1]),
+        ProtocolError::HeaderLen(1)
+    ));
+    assert!(matches!(decode_error(&[0, 0]), ProtocolError::HeaderLen(0)));
+    // Connection frame cut off before header_id
+    assert!(matches!(
+        decode_error(&[0, 5, 4, 0, 0, 0, 2]),
+        ProtocolError::HeaderLen(5)
+    ));
+    // Connection Create without header_proxy_type
+    assert!(matches!(
+        decode_error(&[0, 9, 4, 0, 0, 0, 0, 0, 1, 0, 7]),
+        ProtocolError::HeaderLen(9)
+    ));
+    assert!(matches!(
+        decode_error(&[0, 3, 42, 0, 0]),
+        ProtocolError::DataType(42)
+    ));
+    assert!(matches!(
+        decode_error(&[0, 9, 4, 0, 0, 0, 2, 0, 9, 0, 7]),
+        ProtocolError::HeaderOrigin(9)
+    ));
+}
+
+#[test]
+fn oversized_data_is_rejected_before_buffering() {
+    // Only the header has arrived, the payload would be 0x0401 bytes
+    assert!(matches!(
+        decode_error(&[0, 3, 1, 4, 1]),
+        ProtocolError::DataLen {
+            data_len: 1025,
+            max_data_size: 1024
+        }
+    ));
+
+    let mut codec = RevshCodec::new().max_data_size(1024);
+    let message = Message::new().data_type(DataType::Tty).data(vec![0; 1025]);
+    let mut buf = BytesMut::new();
+    assert!(matches!(
+        codec.encode(message, &mut buf),
+        Err(ProtocolError::DataLen { .. })
+    ));
+}
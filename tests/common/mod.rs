@@ -0,0 +1,406 @@
+#![allow(dead_code)]
+
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::{X509NameBuilder, X509};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio_native_tls::native_tls;
+use tokio_native_tls::{TlsConnector, TlsStream};
+use tokio_util::codec::Framed;
+
+use revsh::codec::RevshCodec;
+use revsh::forward::ForwardSpec;
+use revsh::frame::Frame;
+use revsh::message::{HeaderOrigin, ProxyType};
+use revsh::transport::{Acceptor, Incoming};
+
+/// A connection the mock target dialed or accepted.
+struct MockConnection {
+    writer: OwnedWriteHalf,
+    /// Set while the control has the connection Dormant
+    paused: watch::Sender<bool>,
+}
+
+type Connections = Arc<Mutex<HashMap<(HeaderOrigin, u16), MockConnection>>>;
+
+/// Writes a throwaway self-signed identity for `Control::new`.
+pub fn identity_file() -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&pkey).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&pkey, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    #[allow(deprecated)]
+    let identity = Pkcs12::builder()
+        .build("", "revsh-test", &pkey, &cert)
+        .unwrap();
+    let path = std::env::temp_dir().join(format!(
+        "revsh-test-{}-{}.pfx",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, identity.to_der().unwrap()).unwrap();
+    path
+}
+
+/// Hands out in-memory streams queued by the test.
+pub struct DuplexAcceptor(pub mpsc::UnboundedReceiver<DuplexStream>);
+
+impl Acceptor for DuplexAcceptor {
+    type Stream = DuplexStream;
+
+    async fn accept(&mut self) -> Result<Incoming<DuplexStream>> {
+        match self.0.recv().await {
+            Some(stream) => Ok(Incoming::ready("duplex".to_string(), stream)),
+            None => bail!("No more streams"),
+        }
+    }
+}
+
+/// Reads until `needle` shows up and returns everything read.
+pub async fn read_until<T: AsyncReadExt + Unpin>(stream: &mut T, needle: &str) -> String {
+    let mut read = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !String::from_utf8_lossy(&read).contains(needle) {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("timed out")
+            .unwrap();
+        assert!(
+            n > 0,
+            "EOF before {:?} in {:?}",
+            needle,
+            String::from_utf8_lossy(&read)
+        );
+        read.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(read).unwrap()
+}
+
+/// Waits for the target to have been told `expected`.
+pub async fn wait_for_winsize(winsize: &std::sync::Mutex<(u16, u16)>, expected: (u16, u16)) {
+    for _ in 0..250 {
+        if *winsize.lock().unwrap() == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Window size never got to {:?}", expected);
+}
+
+/// A port that was free a moment ago.
+pub async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// TCP server answering every connection with its own bytes.
+pub async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+    address
+}
+
+/// The target side of revsh, enough to drive `Control` and `Broker`
+/// without the C implementation.
+///
+/// Tty data is echoed back, Winresize is recorded and Connections are
+/// dialed or, for remote forwards, accepted on local listeners. As with the
+/// C target a successful dial isn't reported, a failed one gets an Error
+/// naming the destination and a Destroy. A Dormant connection isn't read
+/// from until it is Active again.
+pub struct MockTarget<S = TlsStream<TcpStream>> {
+    pub shell: String,
+    pub env: String,
+    pub winsize: Arc<std::sync::Mutex<(u16, u16)>>,
+    /// Specs of every Proxy Create received
+    pub proxies: Arc<std::sync::Mutex<Vec<String>>>,
+    /// Follows the Error for a failed dial with a Destroy
+    pub destroy_failed_connects: bool,
+    /// Keeps reading Dormant connections, as a target that overruns the
+    /// control would
+    pub ignore_dormant: bool,
+    /// Connection Dormant, Active and Destroy frames received, in order
+    pub received: Arc<std::sync::Mutex<Vec<Frame>>>,
+    framed: Framed<S, RevshCodec>,
+    sender: mpsc::Sender<Frame>,
+    receiver: mpsc::Receiver<Frame>,
+}
+
+impl MockTarget {
+    /// Calls back to `address` and runs the handshake and Init sequence.
+    pub async fn connect(address: SocketAddr, message_data_size: u16) -> Result<Self> {
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()?;
+        let stream = TcpStream::connect(address).await?;
+        let stream = TlsConnector::from(connector)
+            .connect("localhost", stream)
+            .await?;
+        Self::handshake(stream, message_data_size).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static> MockTarget<S> {
+    /// Runs the handshake and Init sequence over any stream.
+    pub async fn handshake(mut stream: S, message_data_size: u16) -> Result<Self> {
+        stream.write_all(&[0, 1, 0, 0]).await?;
+        let mut version = [0u8; 4];
+        stream.read_exact(&mut version).await?;
+        stream
+            .write_all(&u16::to_be_bytes(message_data_size))
+            .await?;
+        let mut data_size = [0u8; 2];
+        stream.read_exact(&mut data_size).await?;
+        let message_data_size = message_data_size.min(u16::from_be_bytes(data_size));
+
+        let mut framed = Framed::new(stream, RevshCodec::new().max_data_size(message_data_size));
+        // Bounded so a busy connection is held back by the control, as
+        // with the C target blocking on its write
+        let (sender, receiver) = mpsc::channel(64);
+        let interactive = Self::next_init(&mut framed).await?;
+        framed.send(Frame::Init(interactive)).await?;
+        let shell = Self::next_init(&mut framed).await?;
+        let env = Self::next_init(&mut framed).await?;
+        let termios = Self::next_init(&mut framed).await?;
+        if termios.len() != 4 {
+            bail!("Bad termios {:?}", termios);
+        }
+
+        Ok(Self {
+            shell: String::from_utf8_lossy(&shell).to_string(),
+            env: String::from_utf8_lossy(&env).to_string(),
+            winsize: Arc::new(std::sync::Mutex::new((
+                u16::from_be_bytes([termios[0], termios[1]]),
+                u16::from_be_bytes([termios[2], termios[3]]),
+            ))),
+            proxies: Arc::new(std::sync::Mutex::new(vec![])),
+            destroy_failed_connects: true,
+            ignore_dormant: false,
+            received: Arc::new(std::sync::Mutex::new(vec![])),
+            framed,
+            sender,
+            receiver,
+        })
+    }
+
+    async fn next_init(framed: &mut Framed<S, RevshCodec>) -> Result<Bytes> {
+        match framed.next().await {
+            Some(message) => match Frame::try_from(message?)? {
+                Frame::Init(data) => Ok(data),
+                frame => bail!("Expected Init, got {:?}", frame),
+            },
+            None => bail!("Closed during Init"),
+        }
+    }
+
+    /// Sends frames to the control alongside the ones the mock sends itself.
+    pub fn sender(&self) -> mpsc::Sender<Frame> {
+        self.sender.clone()
+    }
+
+    /// Serves the session until the control side goes away.
+    pub async fn run(self) -> Result<()> {
+        let (mut sink, mut stream) = self.framed.split();
+        let sender = self.sender;
+        let mut receiver = self.receiver;
+        tokio::spawn(async move {
+            while let Some(frame) = receiver.recv().await {
+                if sink.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        let next_id = Arc::new(AtomicU16::new(0));
+        let mut proxies = HashMap::new();
+        while let Some(message) = stream.next().await {
+            match Frame::try_from(message?)? {
+                Frame::Tty(data) => sender.send(Frame::Tty(data)).await?,
+                Frame::Winresize { rows, cols } => {
+                    *self.winsize.lock().unwrap() = (rows, cols);
+                }
+                Frame::ProxyCreate {
+                    origin, id, spec, ..
+                } => {
+                    self.proxies.lock().unwrap().push(spec.clone());
+                    if let Ok(forward) = spec.parse::<ForwardSpec>() {
+                        if let Ok(listener) = TcpListener::bind(forward.listen_address()).await {
+                            let task = tokio::spawn(Self::forward_listener(
+                                listener,
+                                forward.connection_string(),
+                                connections.clone(),
+                                next_id.clone(),
+                                sender.clone(),
+                            ));
+                            proxies.insert((origin, id), task);
+                        }
+                    }
+                }
+                Frame::ProxyDestroy { origin, id } => {
+                    if let Some(task) = proxies.remove(&(origin, id)) {
+                        task.abort();
+                    }
+                }
+                Frame::ConnectionCreate {
+                    origin,
+                    id,
+                    destination,
+                    ..
+                } => match TcpStream::connect(&destination).await {
+                    Ok(stream) => Self::attach(stream, (origin, id), &connections, &sender).await,
+                    Err(e) => {
+                        sender
+                            .send(Frame::Error(format!(
+                                "proxy_connect(\"{}\"): {}",
+                                destination, e
+                            )))
+                            .await?;
+                        if self.destroy_failed_connects {
+                            sender.send(Frame::ConnectionDestroy { origin, id }).await?;
+                        }
+                    }
+                },
+                Frame::ConnectionData { origin, id, data } => {
+                    if let Some(connection) = connections.lock().await.get_mut(&(origin, id)) {
+                        connection.writer.write_all(&data).await?;
+                    }
+                }
+                Frame::ConnectionDestroy { origin, id } => {
+                    self.received
+                        .lock()
+                        .unwrap()
+                        .push(Frame::ConnectionDestroy { origin, id });
+                    if let Some(mut connection) = connections.lock().await.remove(&(origin, id)) {
+                        let _ = connection.writer.shutdown().await;
+                    }
+                }
+                Frame::ConnectionDormant { origin, id } => {
+                    self.received
+                        .lock()
+                        .unwrap()
+                        .push(Frame::ConnectionDormant { origin, id });
+                    if let Some(connection) = connections.lock().await.get(&(origin, id)) {
+                        let _ = connection.paused.send(!self.ignore_dormant);
+                    }
+                }
+                Frame::ConnectionActive { origin, id } => {
+                    self.received
+                        .lock()
+                        .unwrap()
+                        .push(Frame::ConnectionActive { origin, id });
+                    if let Some(connection) = connections.lock().await.get(&(origin, id)) {
+                        let _ = connection.paused.send(false);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn forward_listener(
+        listener: TcpListener,
+        destination: String,
+        connections: Connections,
+        next_id: Arc<AtomicU16>,
+        sender: mpsc::Sender<Frame>,
+    ) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let id = next_id.fetch_add(1, Ordering::Relaxed);
+            let _ = sender
+                .send(Frame::ConnectionCreate {
+                    origin: HeaderOrigin::Target,
+                    id,
+                    proxy_type: ProxyType::Static,
+                    destination: destination.clone(),
+                })
+                .await;
+            Self::attach(stream, (HeaderOrigin::Target, id), &connections, &sender).await;
+        }
+    }
+
+    /// Registers the write half and pumps the read half to the control.
+    async fn attach(
+        stream: TcpStream,
+        key: (HeaderOrigin, u16),
+        connections: &Connections,
+        sender: &mpsc::Sender<Frame>,
+    ) {
+        let (mut r, writer) = stream.into_split();
+        let (paused, mut dormant) = watch::channel(false);
+        connections
+            .lock()
+            .await
+            .insert(key, MockConnection { writer, paused });
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let mut buf = BytesMut::with_capacity(1024);
+            loop {
+                if *dormant.borrow_and_update() {
+                    if dormant.changed().await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                buf.reserve(1024);
+                match r.read_buf(&mut buf).await {
+                    Ok(n) if n > 0 => {
+                        let _ = sender
+                            .send(Frame::ConnectionData {
+                                origin: key.0,
+                                id: key.1,
+                                data: buf.split().freeze(),
+                            })
+                            .await;
+                    }
+                    _ => break,
+                }
+            }
+            let _ = sender
+                .send(Frame::ConnectionDestroy {
+                    origin: key.0,
+                    id: key.1,
+                })
+                .await;
+        });
+    }
+}
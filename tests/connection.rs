@@ -0,0 +1,138 @@
+use bytes::Bytes;
+use std::net::Ipv4Addr;
+use tokio::sync::oneshot;
+
+use revsh::connection::{
+    ConnectionTable, IdAllocator, ProxyConnection, QUEUE_HIGH_WATERMARK, QUEUE_LIMIT,
+    QUEUE_LOW_WATERMARK,
+};
+use revsh::socks::{SocksAddress, SocksRequest, SocksVersion};
+
+fn pending(address: Ipv4Addr, port: u16) -> ProxyConnection {
+    let request = SocksRequest {
+        version: SocksVersion::V5,
+        address: SocksAddress::Ipv4(address),
+        port,
+    };
+    let destination = request.connection_string();
+    let (ready, _) = oneshot::channel();
+    ProxyConnection::new()
+        .destination(destination)
+        .pending(request, ready)
+}
+
+#[test]
+fn error_names_a_pending_connect() {
+    let mut connections = ConnectionTable::new();
+    let web = connections
+        .insert_local(pending(Ipv4Addr::new(10, 0, 0, 1), 80))
+        .unwrap();
+    connections
+        .insert_local(pending(Ipv4Addr::new(10, 0, 0, 1), 8080))
+        .unwrap();
+    connections
+        .insert_local(pending(Ipv4Addr::new(110, 0, 0, 1), 80))
+        .unwrap();
+
+    assert_eq!(
+        connections.pending_named("proxy_connect(\"10.0.0.1:80\"): Connection refused"),
+        [web]
+    );
+    assert_eq!(connections.pending_named("10.0.0.1:80: refused"), [web]);
+    assert!(connections.pending_named("Out of memory").is_empty());
+
+    // Two connects to the same destination, either could be meant
+    let other = connections
+        .insert_local(pending(Ipv4Addr::new(10, 0, 0, 1), 80))
+        .unwrap();
+    let mut named = connections.pending_named("proxy_connect(\"10.0.0.1:80\"): Connection refused");
+    named.sort_by_key(|key| key.1);
+    assert_eq!(named, [web, other]);
+}
+
+#[test]
+fn retired_ids_wait_for_the_target() {
+    let mut connections = ConnectionTable::new();
+    let key = connections.insert_local(ProxyConnection::new()).unwrap();
+    assert!(connections.retire(&key).is_some());
+    assert!(connections.get(&key).is_none());
+
+    // Ids in use or retired are not handed out again
+    let mut keys = vec![];
+    while let Some(other) = connections.insert_local(ProxyConnection::new()) {
+        keys.push(other);
+    }
+    assert!(!keys.contains(&key));
+
+    connections.release_retired(&key);
+    assert_eq!(connections.insert_local(ProxyConnection::new()), Some(key));
+}
+
+#[test]
+fn ids_are_not_reused_before_running_out() {
+    let mut ids = IdAllocator::new();
+    assert_eq!(ids.allocate(), Some(0));
+    assert_eq!(ids.allocate(), Some(1));
+    ids.release(0);
+    assert_eq!(ids.allocate(), Some(2));
+
+    for id in 3..=u16::MAX {
+        assert_eq!(ids.allocate(), Some(id));
+    }
+    ids.release(5);
+    ids.release(3);
+    // Releasing twice frees it once
+    ids.release(3);
+    // Freed ids come back oldest first
+    assert_eq!(ids.allocate(), Some(0));
+    assert_eq!(ids.allocate(), Some(5));
+    assert_eq!(ids.allocate(), Some(3));
+    assert_eq!(ids.allocate(), None);
+}
+
+#[test]
+fn send_queue_pauses_and_resumes_the_target() {
+    let mut connection = ProxyConnection::new();
+    let local_end = connection.take_local_end().unwrap();
+    let chunk = 64 * 1024;
+
+    let mut queued = 0;
+    while queued + chunk < QUEUE_HIGH_WATERMARK {
+        assert!(!connection.queue_data(Bytes::from(vec![0; chunk])).unwrap());
+        queued += chunk;
+    }
+    // Dormant once when the high watermark is reached
+    assert!(connection.queue_data(Bytes::from(vec![0; chunk])).unwrap());
+    queued += chunk;
+    assert!(!connection.queue_data(Bytes::from(vec![0; chunk])).unwrap());
+    queued += chunk;
+
+    // Active once when the queue is down to the low watermark
+    while queued - chunk > QUEUE_LOW_WATERMARK {
+        assert!(!local_end.flow.drained(chunk));
+        queued -= chunk;
+    }
+    assert!(local_end.flow.drained(chunk));
+    assert!(!local_end.flow.drained(chunk));
+}
+
+#[test]
+fn send_queue_overflow_fails() {
+    let mut connection = ProxyConnection::new();
+    let _local_end = connection.take_local_end().unwrap();
+    connection
+        .queue_data(Bytes::from(vec![0; QUEUE_LIMIT]))
+        .unwrap();
+    assert!(connection.queue_data(Bytes::from_static(b"x")).is_err());
+}
+
+#[test]
+fn target_dormant_pauses_the_local_end() {
+    let mut connection = ProxyConnection::new();
+    let local_end = connection.take_local_end().unwrap();
+    assert!(!*local_end.paused.borrow());
+    connection.set_paused(true);
+    assert!(*local_end.paused.borrow());
+    connection.set_paused(false);
+    assert!(!*local_end.paused.borrow());
+}
@@ -0,0 +1,134 @@
+/*
+ * Writes the harness frames, one frame per file, with a message_push()
+ * written after the one in the C revsh (io.c): the header layout, the
+ * header_len accounting and the raw header_type check deciding on
+ * header_proxy_type. It is a reimplementation, not the C revsh, so the frames
+ * show how we read that code rather than what a target sends. They don't
+ * come from the Rust encoder they test either.
+ *
+ *   cc -Wall -o harness harness.c && ./harness .
+ */
+
+#include <arpa/inet.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define DT_INIT 0
+#define DT_TTY 1
+#define DT_WINRESIZE 2
+#define DT_PROXY 3
+#define DT_CONNECTION 4
+#define DT_NOP 5
+#define DT_ERROR 6
+
+#define DT_PROXY_HT_CREATE 0
+#define DT_PROXY_HT_DESTROY 1
+#define DT_PROXY_HT_REPORT 2
+
+#define DT_CONNECTION_HT_CREATE 0
+#define DT_CONNECTION_HT_DESTROY 1
+#define DT_CONNECTION_HT_DATA 2
+#define DT_CONNECTION_HT_DORMANT 3
+#define DT_CONNECTION_HT_ACTIVE 4
+
+#define PROXY_STATIC 0
+#define PROXY_DYNAMIC 1
+#define PROXY_TUN 2
+#define PROXY_TAP 3
+
+#define HEADER_ORIGIN_CONTROL 0
+#define HEADER_ORIGIN_TARGET 1
+
+struct message_helper {
+	unsigned char data_type;
+	unsigned short data_len;
+	unsigned short header_type;
+	unsigned short header_origin;
+	unsigned short header_id;
+	unsigned short header_proxy_type;
+	const char *data;
+};
+
+static void push_short(FILE *out, unsigned short value){
+	unsigned short tmp_short = htons(value);
+
+	fwrite(&tmp_short, sizeof(tmp_short), 1, out);
+}
+
+static void message_push(FILE *out, const struct message_helper *message){
+	unsigned short header_len;
+
+	header_len = sizeof(message->data_type) + sizeof(message->data_len);
+	if(message->data_type == DT_PROXY || message->data_type == DT_CONNECTION){
+		header_len += sizeof(message->header_type) + sizeof(message->header_origin) + sizeof(message->header_id);
+		if(message->header_type == DT_PROXY_HT_CREATE || message->header_type == DT_PROXY_HT_REPORT){
+			header_len += sizeof(message->header_proxy_type);
+		}
+	}
+
+	push_short(out, header_len);
+	fwrite(&message->data_type, sizeof(message->data_type), 1, out);
+	push_short(out, message->data_len);
+
+	if(message->data_type == DT_PROXY || message->data_type == DT_CONNECTION){
+		push_short(out, message->header_type);
+		push_short(out, message->header_origin);
+		push_short(out, message->header_id);
+		if(message->header_type == DT_PROXY_HT_CREATE || message->header_type == DT_PROXY_HT_REPORT){
+			push_short(out, message->header_proxy_type);
+		}
+	}
+
+	fwrite(message->data, 1, message->data_len, out);
+}
+
+struct golden {
+	const char *file;
+	struct message_helper message;
+};
+
+/* The C revsh sends the connect string of a Connection Create with its NUL */
+static const struct golden frames[] = {
+	{"init_interactive", {DT_INIT, 1, 0, 0, 0, 0, "\x01"}},
+	{"tty", {DT_TTY, 3, 0, 0, 0, 0, "id\n"}},
+	{"tty_empty", {DT_TTY, 0, 0, 0, 0, 0, ""}},
+	{"winresize", {DT_WINRESIZE, 4, 0, 0, 0, 0, "\x00\x18\x00\x50"}},
+	{"proxy_create_dynamic", {DT_PROXY, 4, DT_PROXY_HT_CREATE, HEADER_ORIGIN_CONTROL, 0, PROXY_DYNAMIC, "1080"}},
+	{"proxy_create_static", {DT_PROXY, 7, DT_PROXY_HT_CREATE, HEADER_ORIGIN_CONTROL, 5, PROXY_STATIC, "22:host"}},
+	{"proxy_destroy", {DT_PROXY, 0, DT_PROXY_HT_DESTROY, HEADER_ORIGIN_TARGET, 5, 0, ""}},
+	{"proxy_report_tun", {DT_PROXY, 2, DT_PROXY_HT_REPORT, HEADER_ORIGIN_TARGET, 5, PROXY_TUN, "ok"}},
+	{"connection_create_dynamic", {DT_CONNECTION, 12, DT_CONNECTION_HT_CREATE, HEADER_ORIGIN_CONTROL, 0x0102, PROXY_DYNAMIC, "10.0.0.1:22\0"}},
+	{"connection_create_static", {DT_CONNECTION, 15, DT_CONNECTION_HT_CREATE, HEADER_ORIGIN_TARGET, 9, PROXY_STATIC, "127.0.0.1:8080\0"}},
+	{"connection_destroy_target", {DT_CONNECTION, 0, DT_CONNECTION_HT_DESTROY, HEADER_ORIGIN_TARGET, 0xffff, 0, ""}},
+	{"connection_destroy_control", {DT_CONNECTION, 0, DT_CONNECTION_HT_DESTROY, HEADER_ORIGIN_CONTROL, 3, 0, ""}},
+	{"connection_data_target", {DT_CONNECTION, 2, DT_CONNECTION_HT_DATA, HEADER_ORIGIN_TARGET, 7, 0, "hi"}},
+	{"connection_data_control", {DT_CONNECTION, 5, DT_CONNECTION_HT_DATA, HEADER_ORIGIN_CONTROL, 3, 0, "GET /"}},
+	{"connection_dormant", {DT_CONNECTION, 0, DT_CONNECTION_HT_DORMANT, HEADER_ORIGIN_CONTROL, 7, 0, ""}},
+	{"connection_active", {DT_CONNECTION, 0, DT_CONNECTION_HT_ACTIVE, HEADER_ORIGIN_TARGET, 7, 0, ""}},
+	{"nop", {DT_NOP, 0, 0, 0, 0, 0, ""}},
+	{"error", {DT_ERROR, 4, 0, 0, 0, 0, "fail"}},
+};
+
+int main(int argc, char **argv){
+	char path[4096];
+	FILE *out;
+	size_t i;
+
+	if(argc != 2){
+		fprintf(stderr, "usage: %s DIR\n", argv[0]);
+		return 1;
+	}
+
+	for(i = 0; i < sizeof(frames) / sizeof(frames[0]); i++){
+		snprintf(path, sizeof(path), "%s/%s.bin", argv[1], frames[i].file);
+		if((out = fopen(path, "wb")) == NULL){
+			perror(path);
+			return 1;
+		}
+		message_push(out, &frames[i].message);
+		fclose(out);
+	}
+
+	return 0;
+}
@@ -0,0 +1,71 @@
+use bytes::Bytes;
+
+use revsh::escape::{Action, EscapeParser};
+
+fn send(data: &'static [u8]) -> Action {
+    Action::Send(Bytes::from_static(data))
+}
+
+fn echo(data: &'static [u8]) -> Action {
+    Action::Echo(Bytes::from_static(data))
+}
+
+#[test]
+fn escapes_only_count_after_a_newline() {
+    let mut parser = EscapeParser::default();
+    assert_eq!(parser.feed(b"~."), [Action::Disconnect]);
+
+    let mut parser = EscapeParser::default();
+    assert_eq!(parser.feed(b"cd ~.\r"), [send(b"cd ~.\r")]);
+    assert_eq!(
+        parser.feed(b"~?~#ls\n"),
+        [Action::Help, Action::ListConnections, send(b"ls\n")]
+    );
+    assert_eq!(parser.feed(b"~."), [Action::Disconnect]);
+}
+
+#[test]
+fn other_characters_pass_through() {
+    let mut parser = EscapeParser::default();
+    assert_eq!(parser.feed(b"~~/bin\r"), [send(b"~/bin\r")]);
+    assert_eq!(parser.feed(b"~x"), [send(b"~x")]);
+
+    // Split across reads
+    let mut parser = EscapeParser::default();
+    assert_eq!(parser.feed(b"echo\r~"), [send(b"echo\r")]);
+    assert_eq!(parser.feed(b"."), [Action::Disconnect]);
+    assert_eq!(parser.feed(b"~"), []);
+    assert_eq!(parser.feed(b"\r"), [send(b"~\r")]);
+    assert_eq!(parser.feed(b"~."), [Action::Disconnect]);
+}
+
+#[test]
+fn command_line_is_edited_locally() {
+    let mut parser = EscapeParser::default();
+    assert_eq!(parser.feed(b"~C"), [echo(b"\r\nrevsh> ")]);
+    assert_eq!(parser.feed(b"-hx\x7f"), [echo(b"-hx\x08 \x08")]);
+    assert_eq!(
+        parser.feed(b"\rpwd\r"),
+        [
+            echo(b"\r\n"),
+            Action::Command("-h".to_string()),
+            send(b"pwd\r")
+        ]
+    );
+
+    // Ctrl-C gives up on the line
+    assert_eq!(
+        parser.feed(b"~C-D\x03~."),
+        [echo(b"\r\nrevsh> "), echo(b"-D\r\n"), Action::Disconnect]
+    );
+}
+
+#[test]
+fn escape_char_is_configurable() {
+    let mut parser = EscapeParser::new(b'%');
+    assert_eq!(parser.feed(b"~.%."), [send(b"~.%.")]);
+    assert!(parser.help().contains(" %.  - terminate session"));
+
+    let mut parser = EscapeParser::new(b'%');
+    assert_eq!(parser.feed(b"%%\r%."), [send(b"%\r"), Action::Disconnect]);
+}
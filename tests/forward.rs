@@ -0,0 +1,54 @@
+use revsh::forward::{ForwardCommand, ForwardSpec};
+
+fn spec(spec: &str) -> ForwardSpec {
+    spec.parse().unwrap()
+}
+
+#[test]
+fn forward_commands() {
+    let parse = |line: &str| line.parse::<ForwardCommand>().unwrap();
+    assert_eq!(parse("-h"), ForwardCommand::Help);
+    assert_eq!(
+        parse("-L 8080:10.0.0.1:80"),
+        ForwardCommand::Local(spec("127.0.0.1:8080:10.0.0.1:80"))
+    );
+    assert_eq!(
+        parse("-R0.0.0.0:2222:localhost:22"),
+        ForwardCommand::Remote(spec("0.0.0.0:2222:localhost:22"))
+    );
+    assert_eq!(
+        parse("-D 1080"),
+        ForwardCommand::Dynamic("127.0.0.1:1080".to_string())
+    );
+    assert_eq!(
+        parse("-D[::1]:1080"),
+        ForwardCommand::Dynamic("[::1]:1080".to_string())
+    );
+    assert_eq!(
+        parse("-KD 0.0.0.0:1080"),
+        ForwardCommand::CancelDynamic("0.0.0.0:1080".to_string())
+    );
+    assert_eq!(
+        parse("-KL8080"),
+        ForwardCommand::CancelLocal("127.0.0.1:8080".to_string())
+    );
+    assert_eq!(
+        parse("  -KR 2222 "),
+        ForwardCommand::CancelRemote("127.0.0.1:2222".to_string())
+    );
+}
+
+#[test]
+fn bad_forward_commands() {
+    for line in [
+        "-X 1",
+        "-D",
+        "-L 8080",
+        "-D host:port",
+        "-KD 1:2:3",
+        "ls",
+        "-KX 1",
+    ] {
+        assert!(line.parse::<ForwardCommand>().is_err(), "{}", line);
+    }
+}
@@ -0,0 +1,159 @@
+use bytes::{Bytes, BytesMut};
+
+use revsh::frame::Frame;
+use revsh::message::{DataType, HeaderOrigin, Message, ProtocolError, ProxyType};
+
+fn frames() -> Vec<Frame> {
+    vec![
+        Frame::Init(Bytes::from_static(b"/bin/sh")),
+        Frame::Tty(Bytes::from_static(b"id\n")),
+        Frame::Winresize { rows: 24, cols: 80 },
+        Frame::ProxyCreate {
+            origin: HeaderOrigin::Control,
+            id: 0,
+            proxy_type: ProxyType::Dynamic,
+            spec: "1080:127.0.0.1:1081".to_string(),
+        },
+        Frame::ProxyDestroy {
+            origin: HeaderOrigin::Control,
+            id: 3,
+        },
+        Frame::ProxyReport {
+            origin: HeaderOrigin::Target,
+            id: 3,
+            proxy_type: ProxyType::Static,
+            data: Bytes::from_static(b"report"),
+        },
+        Frame::ConnectionCreate {
+            origin: HeaderOrigin::Target,
+            id: 7,
+            proxy_type: ProxyType::Static,
+            destination: "127.0.0.1:22".to_string(),
+        },
+        Frame::ConnectionDestroy {
+            origin: HeaderOrigin::Control,
+            id: 7,
+        },
+        Frame::ConnectionData {
+            origin: HeaderOrigin::Target,
+            id: 7,
+            data: Bytes::from_static(b"SSH-2.0"),
+        },
+        Frame::ConnectionDormant {
+            origin: HeaderOrigin::Control,
+            id: 7,
+        },
+        Frame::ConnectionActive {
+            origin: HeaderOrigin::Target,
+            id: 7,
+        },
+        Frame::Nop,
+        Frame::Error("connect failed".to_string()),
+    ]
+}
+
+#[test]
+fn frames_survive_the_wire() {
+    for frame in frames() {
+        let mut buf = BytesMut::new();
+        Message::from(frame.clone()).encode(&mut buf).unwrap();
+        let message = Message::decode(&mut buf, u16::MAX).unwrap().unwrap();
+        assert_eq!(Frame::try_from(message).unwrap(), frame);
+        assert!(buf.is_empty());
+    }
+}
+
+#[test]
+fn proxy_and_connection_destroy_differ() {
+    let proxy = Message::from(Frame::ProxyDestroy {
+        origin: HeaderOrigin::Control,
+        id: 1,
+    });
+    let connection = Message::from(Frame::ConnectionDestroy {
+        origin: HeaderOrigin::Control,
+        id: 1,
+    });
+    assert_eq!(proxy.header_type, connection.header_type);
+    assert!(matches!(
+        Frame::try_from(proxy).unwrap(),
+        Frame::ProxyDestroy { .. }
+    ));
+    assert!(matches!(
+        Frame::try_from(connection).unwrap(),
+        Frame::ConnectionDestroy { .. }
+    ));
+}
+
+#[test]
+fn invalid_combinations_are_rejected() {
+    let unknown_header_type = Message {
+        header_type: 9,
+        ..Message::new().data_type(DataType::Connection)
+    };
+    assert!(matches!(
+        Frame::try_from(unknown_header_type),
+        Err(ProtocolError::HeaderType(9))
+    ));
+
+    let unknown_proxy_type = Message {
+        header_proxy_type: 4,
+        ..Message::new().data_type(DataType::Proxy)
+    };
+    assert!(matches!(
+        Frame::try_from(unknown_proxy_type),
+        Err(ProtocolError::HeaderProxyType(4))
+    ));
+
+    let short_winresize = Message::new()
+        .data_type(DataType::Winresize)
+        .data(vec![0, 24]);
+    assert!(matches!(
+        Frame::try_from(short_winresize),
+        Err(ProtocolError::WinresizeLen(2))
+    ));
+}
+
+#[test]
+fn connection_create_is_nul_terminated() {
+    let message = Message::from(Frame::ConnectionCreate {
+        origin: HeaderOrigin::Control,
+        id: 1,
+        proxy_type: ProxyType::Dynamic,
+        destination: "10.0.0.1:22".to_string(),
+    });
+    assert_eq!(&message.data[..], b"10.0.0.1:22\0");
+}
+
+#[test]
+fn one_trailing_nul_is_dropped_from_create_strings() {
+    let connection = Message::new()
+        .data_type(DataType::Connection)
+        .header_origin(HeaderOrigin::Target)
+        .header_proxy_type(ProxyType::Static)
+        .data(&b"127.0.0.1:8080\0"[..]);
+    assert!(matches!(
+        Frame::try_from(connection).unwrap(),
+        Frame::ConnectionCreate { destination, .. } if destination == "127.0.0.1:8080"
+    ));
+
+    let proxy = Message::new()
+        .data_type(DataType::Proxy)
+        .header_origin(HeaderOrigin::Target)
+        .header_proxy_type(ProxyType::Static)
+        .data(&b"22:host\0"[..]);
+    assert!(matches!(
+        Frame::try_from(proxy).unwrap(),
+        Frame::ProxyCreate { spec, .. } if spec == "22:host"
+    ));
+
+    // Only the terminator goes, and a string without one is left alone
+    let proxy = Message::new()
+        .data_type(DataType::Proxy)
+        .header_origin(HeaderOrigin::Target)
+        .header_proxy_type(ProxyType::Static)
+        .data(&b"22:host\0\0"[..]);
+    assert!(matches!(
+        Frame::try_from(proxy).unwrap(),
+        Frame::ProxyCreate { spec, .. } if spec == "22:host\0"
+    ));
+}
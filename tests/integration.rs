@@ -0,0 +1,778 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::sync::{mpsc, watch};
+
+use common::{
+    echo_server, free_port, identity_file, read_until, wait_for_winsize, DuplexAcceptor, MockTarget,
+};
+use revsh::control::Control;
+use revsh::forward::ForwardSpec;
+use revsh::frame::Frame;
+use revsh::message::HeaderOrigin;
+use revsh::version::ProtocolVersion;
+
+struct Session {
+    shell: String,
+    env: String,
+    proxies: Arc<std::sync::Mutex<Vec<String>>>,
+    /// Connection frames the mock target got from the control
+    received: Arc<std::sync::Mutex<Vec<Frame>>>,
+    /// Frames for the mock target to send to the control
+    to_control: mpsc::Sender<Frame>,
+    shell_in: DuplexStream,
+    shell_out: DuplexStream,
+}
+
+/// Runs a control configured by `configure` against a mock target.
+async fn session<F: FnOnce(&mut Control)>(configure: F) -> Session {
+    session_with(configure, |_| {}).await
+}
+
+/// Like `session`, with the mock target adjusted by `configure_target`.
+async fn session_with<F, G>(configure: F, configure_target: G) -> Session
+where
+    F: FnOnce(&mut Control),
+    G: FnOnce(&mut MockTarget),
+{
+    let key_file = identity_file();
+    let mut control = Control::new("127.0.0.1:0".parse().unwrap(), &key_file)
+        .await
+        .unwrap();
+    std::fs::remove_file(key_file).unwrap();
+    configure(&mut control);
+    let address = control.local_addr().unwrap();
+
+    let target = tokio::spawn(MockTarget::connect(address, 1024));
+    let mut broker = control.accept().await.unwrap();
+    let mut target = target.await.unwrap().unwrap();
+    configure_target(&mut target);
+    assert_eq!(broker.message_data_size(), 1024);
+    assert_eq!(broker.protocol_version(), ProtocolVersion::new(1, 0));
+
+    let (shell_in, input) = tokio::io::duplex(64 * 1024);
+    let (output, shell_out) = tokio::io::duplex(64 * 1024);
+    broker.input(input).output(output);
+    tokio::spawn(broker.run());
+
+    let session = Session {
+        shell: target.shell.clone(),
+        env: target.env.clone(),
+        proxies: target.proxies.clone(),
+        received: target.received.clone(),
+        to_control: target.sender(),
+        shell_in,
+        shell_out,
+    };
+    tokio::spawn(target.run());
+    session
+}
+
+/// Connects once the listener started by the broker or target is up.
+async fn connect(address: SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(address).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Nothing listening on {}", address);
+}
+
+async fn read_exactly<T: AsyncReadExt + Unpin>(stream: &mut T, len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+        .await
+        .expect("timed out")
+        .unwrap();
+    buf
+}
+
+async fn assert_echo(stream: &mut TcpStream, data: &[u8]) {
+    stream.write_all(data).await.unwrap();
+    assert_eq!(read_exactly(stream, data.len()).await, data);
+}
+
+fn local(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[tokio::test]
+async fn shell_init_and_echo() {
+    let mut session = session(|control| {
+        control
+            .shell("/bin/bash".to_string())
+            .env(vec!["TERM=xterm".to_string(), "LANG=C".to_string()]);
+    })
+    .await;
+    assert_eq!(session.shell, "/bin/bash");
+    assert_eq!(session.env, "TERM=xterm LANG=C");
+
+    session.shell_in.write_all(b"id\n").await.unwrap();
+    assert_eq!(read_exactly(&mut session.shell_out, 3).await, b"id\n");
+}
+
+#[tokio::test]
+async fn shell_input_is_split_to_message_size() {
+    let mut session = session(|_| {}).await;
+    // The mock target rejects any frame over the negotiated 1024 bytes
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    session.shell_in.write_all(&data).await.unwrap();
+    assert_eq!(read_exactly(&mut session.shell_out, data.len()).await, data);
+}
+
+#[tokio::test]
+async fn socks5_connect() {
+    let echo = echo_server().await;
+    let proxy = local(free_port().await);
+    let session = session(|control| {
+        control.proxy(Some(proxy)).optimistic_connect(true);
+    })
+    .await;
+
+    let mut stream = connect(proxy).await;
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 2).await, [5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend(echo.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 10).await[..2], [5, 0]);
+
+    assert_echo(&mut stream, b"hello through socks5").await;
+    let data = vec![0x41; 100 * 1024];
+    assert_echo(&mut stream, &data).await;
+
+    // The SOCKS listener is local only, nothing listens on the target
+    assert!(session.proxies.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn socks4a_connect() {
+    let echo = echo_server().await;
+    let proxy = local(free_port().await);
+    let _session = session(|control| {
+        control.proxy(Some(proxy)).optimistic_connect(true);
+    })
+    .await;
+
+    let mut stream = connect(proxy).await;
+    let mut request = vec![4, 1];
+    request.extend(echo.port().to_be_bytes());
+    request.extend([0, 0, 0, 1]);
+    request.extend(b"user\0localhost\0");
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 8).await[..2], [0, 90]);
+
+    assert_echo(&mut stream, b"hello through socks4a").await;
+}
+
+#[tokio::test]
+async fn socks_connect_refused() {
+    let closed = free_port().await;
+    let proxy = local(free_port().await);
+    let _session = session(|control| {
+        control.proxy(Some(proxy));
+    })
+    .await;
+
+    let mut stream = connect(proxy).await;
+    let mut request = vec![4, 1];
+    request.extend(closed.to_be_bytes());
+    request.extend([127, 0, 0, 1, 0]);
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 8).await[..2], [0, 91]);
+}
+
+#[tokio::test]
+async fn socks_connect_refused_by_error_only() {
+    let closed = free_port().await;
+    let proxy = local(free_port().await);
+    let _session = session_with(
+        |control| {
+            control
+                .proxy(Some(proxy))
+                .connect_timeout(Duration::from_secs(60));
+        },
+        |target| target.destroy_failed_connects = false,
+    )
+    .await;
+
+    // Refused long before the connect timeout
+    let mut stream = connect(proxy).await;
+    let mut request = vec![4, 1];
+    request.extend(closed.to_be_bytes());
+    request.extend([127, 0, 0, 1, 0]);
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 8).await[..2], [0, 91]);
+}
+
+#[tokio::test]
+async fn socks_connect_granted_by_server_data() {
+    // Speaks first, like an SSH server
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+        read_until(&mut stream, "\n").await;
+    });
+    let proxy = local(free_port().await);
+    let _session = session(|control| {
+        control
+            .proxy(Some(proxy))
+            .connect_timeout(Duration::from_secs(60));
+    })
+    .await;
+
+    let mut stream = connect(proxy).await;
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 2).await, [5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend(server.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 10).await[..2], [5, 0]);
+    assert_eq!(read_until(&mut stream, "\r\n").await, "SSH-2.0-test\r\n");
+}
+
+/// A server that says nothing until it got a request, like an HTTP server.
+async fn http_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                read_until(&mut stream, "\r\n\r\n").await;
+                stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+            });
+        }
+    });
+    server
+}
+
+#[tokio::test]
+async fn socks_connect_times_out_without_an_answer() {
+    let server = http_server().await;
+    let proxy = local(free_port().await);
+    let _session = session(|control| {
+        control
+            .proxy(Some(proxy))
+            .connect_timeout(Duration::from_millis(200));
+    })
+    .await;
+
+    // Nothing comes back from the target, the connect fails
+    let mut stream = connect(proxy).await;
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 2).await, [5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend(server.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 10).await[..2], [5, 4]);
+    let mut rest = vec![];
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+}
+
+#[tokio::test]
+async fn socks_client_speaks_first_with_optimistic_connect() {
+    let server = http_server().await;
+    let proxy = local(free_port().await);
+    let _session = session(|control| {
+        control
+            .proxy(Some(proxy))
+            .connect_timeout(Duration::from_secs(60))
+            .optimistic_connect(true);
+    })
+    .await;
+
+    // Granted without waiting for the target
+    let mut stream = connect(proxy).await;
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 2).await, [5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend(server.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 10).await[..2], [5, 0]);
+
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    assert!(read_until(&mut stream, "\r\n\r\n")
+        .await
+        .starts_with("HTTP/1.0 200 OK"));
+}
+
+#[tokio::test]
+async fn local_forward() {
+    let echo = echo_server().await;
+    let port = free_port().await;
+    let forward: ForwardSpec = format!("127.0.0.1:{}:127.0.0.1:{}", port, echo.port())
+        .parse()
+        .unwrap();
+    let _session = session(|control| {
+        control.local_forwards(vec![forward]);
+    })
+    .await;
+
+    let mut stream = connect(local(port)).await;
+    assert_echo(&mut stream, b"hello through -L").await;
+
+    // Closing our side reaches the echo server, which closes its side too
+    stream.shutdown().await.unwrap();
+    let mut rest = vec![];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("timed out")
+        .unwrap();
+    assert!(rest.is_empty());
+}
+
+#[tokio::test]
+async fn local_data_after_remote_close() {
+    // Closes its side right away and then reads what it is sent
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let (received, mut receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        received.send(data).unwrap();
+    });
+    let port = free_port().await;
+    let forward: ForwardSpec = format!("127.0.0.1:{}:{}", port, server).parse().unwrap();
+    let _session = session(|control| {
+        control.local_forwards(vec![forward]);
+    })
+    .await;
+
+    // The target's Destroy shows up as EOF, sending still works
+    let mut stream = connect(local(port)).await;
+    let mut rest = vec![];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("timed out")
+        .unwrap();
+    assert!(rest.is_empty());
+    stream.write_all(b"after the remote close").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let data = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .expect("timed out")
+        .unwrap();
+    assert_eq!(data, b"after the remote close");
+}
+
+/// Whether the mock target got `frame` from the control.
+fn has_received(session: &Session, frame: &Frame) -> bool {
+    session.received.lock().unwrap().contains(frame)
+}
+
+/// A local forward to a server whose end is handed to the test.
+async fn forward_to_test(ignore_dormant: bool) -> (Session, TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let port = free_port().await;
+    let forward: ForwardSpec = format!("127.0.0.1:{}:{}", port, server).parse().unwrap();
+    let session = session_with(
+        |control| {
+            control.local_forwards(vec![forward]);
+        },
+        |target| target.ignore_dormant = ignore_dormant,
+    )
+    .await;
+    let stream = connect(local(port)).await;
+    let (server, _) = listener.accept().await.unwrap();
+    (session, stream, server)
+}
+
+#[tokio::test]
+async fn slow_local_peer_pauses_the_target() {
+    let (session, mut stream, mut server) = forward_to_test(false).await;
+    let dormant = Frame::ConnectionDormant {
+        origin: HeaderOrigin::Control,
+        id: 0,
+    };
+    let active = Frame::ConnectionActive {
+        origin: HeaderOrigin::Control,
+        id: 0,
+    };
+
+    // Nothing is read locally until the target was told to pause. The
+    // writes are paced so the Dormant isn't overtaken by megabytes of frames
+    // already on their way, which would overflow the queue.
+    let chunk = [0x5a; 64 * 1024];
+    let mut sent = 0;
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !has_received(&session, &dormant) {
+        assert!(Instant::now() < deadline, "Never paused");
+        if let Ok(n) = tokio::time::timeout(Duration::from_millis(10), server.write(&chunk)).await {
+            sent += n.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert!(!has_received(&session, &active));
+
+    // Reading it all lets the target resume, and nothing was dropped
+    server.shutdown().await.unwrap();
+    let mut data = vec![];
+    tokio::time::timeout(Duration::from_secs(30), stream.read_to_end(&mut data))
+        .await
+        .expect("timed out")
+        .unwrap();
+    assert_eq!(data.len(), sent);
+    assert!(data.iter().all(|&b| b == 0x5a));
+    for _ in 0..100 {
+        if has_received(&session, &active) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("Never resumed");
+}
+
+#[tokio::test]
+async fn target_overrunning_the_send_queue_is_dropped() {
+    let (session, mut stream, mut server) = forward_to_test(true).await;
+    let destroy = Frame::ConnectionDestroy {
+        origin: HeaderOrigin::Control,
+        id: 0,
+    };
+
+    // The target ignores the Dormant and keeps sending to a local peer that
+    // reads nothing
+    let chunk = [0x5a; 64 * 1024];
+    let mut sent = 0;
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !has_received(&session, &destroy) {
+        assert!(Instant::now() < deadline, "Never dropped");
+        if let Ok(n) = tokio::time::timeout(Duration::from_millis(10), server.write(&chunk)).await {
+            sent += n.unwrap();
+        }
+    }
+
+    // What was queued before the overflow is still delivered, then it ends
+    let mut data = vec![];
+    let _ = tokio::time::timeout(Duration::from_secs(30), stream.read_to_end(&mut data))
+        .await
+        .expect("timed out");
+    assert!(data.len() < sent);
+}
+
+#[tokio::test]
+async fn target_dormant_pauses_local_reads() {
+    let echo = echo_server().await;
+    let port = free_port().await;
+    let forward: ForwardSpec = format!("127.0.0.1:{}:127.0.0.1:{}", port, echo.port())
+        .parse()
+        .unwrap();
+    let mut session = session(|control| {
+        control.local_forwards(vec![forward]);
+    })
+    .await;
+    let mut stream = connect(local(port)).await;
+    assert_echo(&mut stream, b"before").await;
+
+    session
+        .to_control
+        .send(Frame::ConnectionDormant {
+            origin: HeaderOrigin::Control,
+            id: 0,
+        })
+        .await
+        .unwrap();
+    // Frames are handled in order, once this is out the Dormant was too
+    session
+        .to_control
+        .send(Frame::Tty("dormant\n".into()))
+        .await
+        .unwrap();
+    read_until(&mut session.shell_out, "dormant").await;
+
+    stream.write_all(b"while dormant").await.unwrap();
+    let mut buf = [0u8; 64];
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buf))
+            .await
+            .is_err()
+    );
+
+    session
+        .to_control
+        .send(Frame::ConnectionActive {
+            origin: HeaderOrigin::Control,
+            id: 0,
+        })
+        .await
+        .unwrap();
+    assert_eq!(read_exactly(&mut stream, 13).await, b"while dormant");
+}
+
+#[tokio::test]
+async fn remote_forward() {
+    let echo = echo_server().await;
+    let port = free_port().await;
+    let forward: ForwardSpec = format!("127.0.0.1:{}:127.0.0.1:{}", port, echo.port())
+        .parse()
+        .unwrap();
+    let _session = session(|control| {
+        // The SOCKS timeout doesn't cut short the local dial
+        control
+            .remote_forwards(vec![forward])
+            .connect_timeout(Duration::ZERO);
+    })
+    .await;
+
+    // The mock target listens on the remote side of the forward and, like
+    // the C one, sends the destination NUL terminated
+    let mut stream = connect(local(port)).await;
+    assert_echo(&mut stream, b"hello through -R").await;
+}
+
+#[tokio::test]
+async fn failed_startup_forward_keeps_the_session() {
+    let forward: ForwardSpec = "127.0.0.1:0:127.0.0.1:22".parse().unwrap();
+    let mut session = session(|control| {
+        control.remote_forwards(vec![forward.clone(), forward.clone()]);
+    })
+    .await;
+
+    // The duplicate is refused, the session goes on
+    session.shell_in.write_all(b"id\n").await.unwrap();
+    assert_eq!(read_exactly(&mut session.shell_out, 3).await, b"id\n");
+    assert_eq!(*session.proxies.lock().unwrap(), [forward.to_string()]);
+}
+
+#[tokio::test]
+async fn escape_sequences() {
+    let echo = echo_server().await;
+    let port = free_port().await;
+    let forward: ForwardSpec = format!("127.0.0.1:{}:127.0.0.1:{}", port, echo.port())
+        .parse()
+        .unwrap();
+    let mut session = session(|control| {
+        control.local_forwards(vec![forward]);
+    })
+    .await;
+    let mut stream = connect(local(port)).await;
+    assert_echo(&mut stream, b"ping").await;
+
+    session.shell_in.write_all(b"~?").await.unwrap();
+    read_until(
+        &mut session.shell_out,
+        "only recognized immediately after newline",
+    )
+    .await;
+
+    // Not at the start of a line, so this goes to the target
+    session.shell_in.write_all(b"ls ~.\r").await.unwrap();
+    assert_eq!(read_exactly(&mut session.shell_out, 6).await, b"ls ~.\r");
+
+    session.shell_in.write_all(b"~#").await.unwrap();
+    let list = read_until(&mut session.shell_out, "(open)\r\n").await;
+    assert!(
+        list.contains(&format!("#0 Control 127.0.0.1:{}", echo.port())),
+        "{}",
+        list
+    );
+
+    session.shell_in.write_all(b"~Chelp\r").await.unwrap();
+    read_until(&mut session.shell_out, "Cancel dynamic forward").await;
+
+    session.shell_in.write_all(b"~~\r").await.unwrap();
+    assert_eq!(read_exactly(&mut session.shell_out, 2).await, b"~\r");
+
+    // Ends the session and closes the output
+    session.shell_in.write_all(b"~.").await.unwrap();
+    read_until(&mut session.shell_out, "Disconnecting").await;
+    let mut rest = vec![];
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        session.shell_out.read_to_end(&mut rest),
+    )
+    .await
+    .expect("timed out")
+    .unwrap();
+}
+
+#[tokio::test]
+async fn escape_char_can_be_turned_off() {
+    let mut session = session(|control| {
+        control.escape_char(None);
+    })
+    .await;
+    session.shell_in.write_all(b"~.~?").await.unwrap();
+    assert_eq!(read_exactly(&mut session.shell_out, 4).await, b"~.~?");
+}
+
+/// Runs `command` on the `~C` command line and returns its reply.
+async fn command(session: &mut Session, command: &str) -> String {
+    session
+        .shell_in
+        .write_all(format!("\r~C{}\r", command).as_bytes())
+        .await
+        .unwrap();
+    let echo = format!("revsh> {}\r\n", command);
+    let read = read_until(&mut session.shell_out, &echo).await;
+    let mut reply = read.split_once(&echo).unwrap().1.to_string();
+    if !reply.contains("\r\n") {
+        reply += &read_until(&mut session.shell_out, "\r\n").await;
+    }
+    reply.split("\r\n").next().unwrap().to_string()
+}
+
+/// Waits for `address` to stop accepting connections.
+async fn assert_closed(address: SocketAddr) {
+    for _ in 0..100 {
+        if TcpStream::connect(address).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Still listening on {}", address);
+}
+
+#[tokio::test]
+async fn forwards_added_and_cancelled_at_runtime() {
+    let echo = echo_server().await;
+    let mut session = session(|control| {
+        control.optimistic_connect(true);
+    })
+    .await;
+
+    let port = free_port().await;
+    let spec = format!("{}:127.0.0.1:{}", port, echo.port());
+    assert_eq!(
+        command(&mut session, &format!("-L{}", spec)).await,
+        format!("Forwarding 127.0.0.1:{} locally", spec)
+    );
+    assert!(command(&mut session, &format!("-L {}", spec))
+        .await
+        .starts_with("Already forwarding"));
+    let mut stream = connect(local(port)).await;
+    assert_echo(&mut stream, b"hello through a new -L").await;
+    assert_eq!(
+        command(&mut session, &format!("-KL {}", port)).await,
+        format!("Cancelled local forward on 127.0.0.1:{}", port)
+    );
+    assert_closed(local(port)).await;
+    // Connections made before keep going
+    assert_echo(&mut stream, b"still there").await;
+
+    let port = free_port().await;
+    command(
+        &mut session,
+        &format!("-R {}:127.0.0.1:{}", port, echo.port()),
+    )
+    .await;
+    let mut stream = connect(local(port)).await;
+    assert_echo(&mut stream, b"hello through a new -R").await;
+    assert_eq!(
+        command(&mut session, &format!("-KR {}", port)).await,
+        format!("Cancelled remote forward on 127.0.0.1:{}", port)
+    );
+    assert_closed(local(port)).await;
+
+    let port = free_port().await;
+    command(&mut session, &format!("-D {}", port)).await;
+    let mut stream = connect(local(port)).await;
+    let mut request = vec![4, 1];
+    request.extend(echo.port().to_be_bytes());
+    request.extend([127, 0, 0, 1, 0]);
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exactly(&mut stream, 8).await[..2], [0, 90]);
+    assert_echo(&mut stream, b"hello through a new -D").await;
+    command(&mut session, &format!("-KD 127.0.0.1:{}", port)).await;
+    assert_closed(local(port)).await;
+    // Only the -R asked the target to listen
+    assert_eq!(session.proxies.lock().unwrap().len(), 1);
+
+    assert_eq!(
+        command(&mut session, "-KD 1").await,
+        "No dynamic forward on 127.0.0.1:1"
+    );
+    assert!(command(&mut session, "-X")
+        .await
+        .starts_with("Invalid command"));
+}
+
+async fn assert_shell_echo<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    input: &mut T,
+    output: &mut T,
+    data: &[u8],
+) {
+    input.write_all(data).await.unwrap();
+    assert_eq!(read_exactly(output, data.len()).await, data);
+}
+
+#[tokio::test]
+async fn session_over_duplex_acceptor() {
+    let (streams, receiver) = mpsc::unbounded_channel();
+    let mut control = Control::with_acceptor(DuplexAcceptor(receiver));
+    let (control_end, target_end) = tokio::io::duplex(64 * 1024);
+    streams.send(control_end).unwrap();
+
+    let target = tokio::spawn(MockTarget::handshake(target_end, 2048));
+    let mut broker = control.accept().await.unwrap();
+    let target = target.await.unwrap().unwrap();
+    assert_eq!(broker.remote_address, "duplex");
+    assert_eq!(broker.message_data_size(), 2048);
+    tokio::spawn(target.run());
+
+    let (mut shell_in, input) = tokio::io::duplex(1024);
+    let (output, mut shell_out) = tokio::io::duplex(1024);
+    broker.input(input).output(output);
+    tokio::spawn(broker.run());
+    assert_shell_echo(&mut shell_in, &mut shell_out, b"whoami\n").await;
+}
+
+#[tokio::test]
+async fn session_over_unix_socket() {
+    let (streams, receiver) = mpsc::unbounded_channel();
+    drop(streams);
+    let mut control = Control::with_acceptor(DuplexAcceptor(receiver));
+    assert!(control.accept().await.is_err());
+
+    let (control_end, target_end) = UnixStream::pair().unwrap();
+    let target = tokio::spawn(MockTarget::handshake(target_end, 1024));
+    let mut broker = control
+        .establish(control_end, "unix".to_string())
+        .await
+        .unwrap();
+    tokio::spawn(target.await.unwrap().unwrap().run());
+
+    let (mut shell_in, input) = tokio::io::duplex(1024);
+    let (output, mut shell_out) = tokio::io::duplex(1024);
+    broker.input(input).output(output);
+    tokio::spawn(broker.run());
+    assert_shell_echo(&mut shell_in, &mut shell_out, b"uname -a\n").await;
+}
+
+#[tokio::test]
+async fn window_size_is_sent_without_traffic() {
+    let mut control = Control::with_acceptor(DuplexAcceptor(mpsc::unbounded_channel().1));
+    control.message_data_size(1024);
+    let (control_end, target_end) = tokio::io::duplex(64 * 1024);
+    let target = tokio::spawn(MockTarget::handshake(target_end, 1024));
+    let mut broker = control
+        .establish(control_end, "duplex".to_string())
+        .await
+        .unwrap();
+    let target = target.await.unwrap().unwrap();
+    let target_winsize = target.winsize.clone();
+    tokio::spawn(target.run());
+
+    let (_shell_in, input) = tokio::io::duplex(1024);
+    let (output, _shell_out) = tokio::io::duplex(1024);
+    let (winsize, receiver) = watch::channel((24, 80));
+    broker.input(input).output(output).winsize(receiver);
+    tokio::spawn(broker.run());
+
+    for size in [(50, 132), (51, 133)] {
+        winsize.send(size).unwrap();
+        wait_for_winsize(&target_winsize, size).await;
+    }
+}
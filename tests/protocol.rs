@@ -0,0 +1,258 @@
+use bytes::{Bytes, BytesMut};
+
+use revsh::frame::Frame;
+use revsh::message::{HeaderOrigin, Message, ProxyType};
+use revsh::protocol::{ControlProtocol, Event, State};
+use revsh::version::ProtocolVersion;
+
+fn encode(frame: Frame) -> Vec<u8> {
+    let mut buf = Vec::new();
+    Message::from(frame).encode(&mut buf).unwrap();
+    buf
+}
+
+fn transmitted(protocol: &mut ControlProtocol) -> Vec<u8> {
+    let mut data = Vec::new();
+    while let Some(bytes) = protocol.poll_transmit() {
+        data.extend_from_slice(&bytes);
+    }
+    data
+}
+
+/// What a revsh 1.0 target sends up to and including its Init answer.
+fn target_handshake(message_data_size: u16) -> Vec<u8> {
+    let mut data = vec![0, 1, 0, 0];
+    data.extend(u16::to_be_bytes(message_data_size));
+    data.extend(encode(Frame::Init(Bytes::from_static(&[0x1]))));
+    data
+}
+
+fn started() -> ControlProtocol {
+    let mut protocol = ControlProtocol::new()
+        .shell("/bin/bash".to_string())
+        .env(vec!["TERM=xterm".to_string(), "LANG=C".to_string()])
+        .winsize(24, 80)
+        .message_data_size(4096);
+    protocol.start();
+    protocol
+}
+
+#[test]
+fn handshake_and_init_sequence() {
+    let mut protocol = started();
+    assert_eq!(transmitted(&mut protocol), vec![0, 1, 0, 0]);
+
+    protocol.receive(&[0, 1, 0, 0]).unwrap();
+    assert_eq!(protocol.state(), State::DataSize);
+    assert_eq!(transmitted(&mut protocol), vec![0x10, 0]);
+
+    protocol.receive(&u16::to_be_bytes(2048)).unwrap();
+    assert_eq!(protocol.state(), State::Init);
+    assert_eq!(
+        transmitted(&mut protocol),
+        encode(Frame::Init(Bytes::from_static(&[0x1])))
+    );
+    assert_eq!(protocol.poll_event(), None);
+
+    protocol
+        .receive(&encode(Frame::Init(Bytes::from_static(&[0x1]))))
+        .unwrap();
+    let mut expected = encode(Frame::Init(Bytes::from_static(b"/bin/bash")));
+    expected.extend(encode(Frame::Init(Bytes::from_static(
+        b"TERM=xterm LANG=C",
+    ))));
+    expected.extend(encode(Frame::Init(Bytes::from_static(&[0, 24, 0, 80]))));
+    assert_eq!(transmitted(&mut protocol), expected);
+    assert_eq!(
+        protocol.poll_event(),
+        Some(Event::Established {
+            version: ProtocolVersion::new(1, 0),
+            message_data_size: 2048,
+        })
+    );
+    assert_eq!(protocol.state(), State::Established);
+}
+
+#[test]
+fn handshake_byte_by_byte() {
+    let mut protocol = started();
+    for byte in target_handshake(u16::MAX) {
+        protocol.receive(&[byte]).unwrap();
+    }
+    assert!(matches!(
+        protocol.poll_event(),
+        Some(Event::Established {
+            message_data_size: 4096,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn incompatible_major_fails() {
+    let mut protocol = started();
+    let error = protocol.receive(&[0, 2, 0, 0]).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Target speaks protocol 2.0, control supports 1.x"
+    );
+    assert_eq!(protocol.state(), State::Failed);
+    assert!(protocol.receive(&[0, 0]).is_err());
+}
+
+#[test]
+fn too_small_data_size_fails() {
+    let mut protocol = started();
+    let error = protocol.receive(&[0, 1, 0, 0, 0x03, 0xff]).unwrap_err();
+    assert_eq!(error.to_string(), "Can't agree on a message size");
+}
+
+#[test]
+fn data_before_start_fails() {
+    let mut protocol = ControlProtocol::new();
+    assert!(protocol.receive(&[0, 1, 0, 0]).is_err());
+}
+
+#[test]
+fn frames_after_handshake_become_events() {
+    let mut protocol = started();
+    let mut data = target_handshake(1024);
+    data.extend(encode(Frame::Tty(Bytes::from_static(b"$ "))));
+    data.extend(encode(Frame::ConnectionCreate {
+        origin: HeaderOrigin::Target,
+        id: 3,
+        proxy_type: ProxyType::Static,
+        destination: "127.0.0.1:22".to_string(),
+    }));
+    // A create claiming to come from the control side is ignored
+    data.extend(encode(Frame::ConnectionCreate {
+        origin: HeaderOrigin::Control,
+        id: 4,
+        proxy_type: ProxyType::Static,
+        destination: "127.0.0.1:22".to_string(),
+    }));
+    data.extend(encode(Frame::ConnectionData {
+        origin: HeaderOrigin::Target,
+        id: 3,
+        data: Bytes::new(),
+    }));
+    protocol.receive(&data).unwrap();
+
+    assert!(matches!(
+        protocol.poll_event(),
+        Some(Event::Established { .. })
+    ));
+    assert_eq!(
+        protocol.poll_event(),
+        Some(Event::Tty(Bytes::from_static(b"$ ")))
+    );
+    assert_eq!(
+        protocol.poll_event(),
+        Some(Event::RemoteConnect {
+            key: (HeaderOrigin::Target, 3),
+            destination: "127.0.0.1:22".to_string(),
+        })
+    );
+    assert_eq!(
+        protocol.poll_event(),
+        Some(Event::RemoteClosed((HeaderOrigin::Target, 3)))
+    );
+    assert_eq!(protocol.poll_event(), None);
+}
+
+#[test]
+fn remote_connect_from_harness_frame_is_dialable() {
+    let mut protocol = started();
+    let mut data = target_handshake(1024);
+    data.extend(include_bytes!("data/harness/connection_create_static.bin"));
+    protocol.receive(&data).unwrap();
+
+    assert!(matches!(
+        protocol.poll_event(),
+        Some(Event::Established { .. })
+    ));
+    match protocol.poll_event() {
+        Some(Event::RemoteConnect { destination, .. }) => {
+            assert_eq!(destination, "127.0.0.1:8080");
+            assert!(destination.parse::<std::net::SocketAddr>().is_ok());
+        }
+        event => panic!("Expected RemoteConnect, got {:?}", event),
+    }
+}
+
+#[test]
+fn partial_frame_is_handed_over() {
+    let mut protocol = started();
+    let mut data = target_handshake(1024);
+    let tty = encode(Frame::Tty(Bytes::from_static(b"uid=0")));
+    data.extend_from_slice(&tty[..4]);
+    protocol.receive(&data).unwrap();
+
+    let mut buffered = protocol.take_buffered();
+    assert_eq!(&buffered[..], &tty[..4]);
+    buffered.extend_from_slice(&tty[4..]);
+    let message = Message::decode(&mut buffered, 1024).unwrap().unwrap();
+    protocol
+        .receive_frame(Frame::try_from(message).unwrap())
+        .unwrap();
+    assert!(matches!(
+        protocol.poll_event(),
+        Some(Event::Established { .. })
+    ));
+    assert_eq!(
+        protocol.poll_event(),
+        Some(Event::Tty(Bytes::from_static(b"uid=0")))
+    );
+}
+
+#[test]
+fn oversized_frames_are_rejected_after_negotiation() {
+    let mut protocol = started();
+    protocol.receive(&target_handshake(1024)).unwrap();
+    let mut frame = BytesMut::new();
+    Message::from(Frame::Tty(Bytes::from(vec![0; 2000])))
+        .encode(&mut frame)
+        .unwrap();
+    assert!(protocol.receive(&frame).is_err());
+}
+
+#[test]
+fn frames_before_the_init_answer_fail() {
+    let mut protocol = started();
+    protocol.receive(&[0, 1, 0, 0, 0x04, 0]).unwrap();
+    assert_eq!(protocol.state(), State::Init);
+    let error = protocol
+        .receive(&encode(Frame::Tty(Bytes::from_static(b"$ "))))
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Expected Init, got Tty(b\"$ \")");
+    assert_eq!(protocol.state(), State::Failed);
+}
+
+#[test]
+fn sent_frames_are_transmitted_once_established() {
+    let mut protocol = started();
+    let tty = Frame::Tty(Bytes::from_static(b"id\n"));
+    assert!(protocol.send(tty.clone()).is_err());
+
+    protocol.receive(&target_handshake(1024)).unwrap();
+    transmitted(&mut protocol);
+    protocol.send(tty.clone()).unwrap();
+    protocol
+        .send(Frame::ConnectionDestroy {
+            origin: HeaderOrigin::Control,
+            id: 2,
+        })
+        .unwrap();
+    let mut expected = encode(tty);
+    expected.extend(encode(Frame::ConnectionDestroy {
+        origin: HeaderOrigin::Control,
+        id: 2,
+    }));
+    assert_eq!(transmitted(&mut protocol), expected);
+
+    // Splitting is up to the caller
+    assert!(protocol
+        .send(Frame::Tty(Bytes::from(vec![0; 2000])))
+        .is_err());
+    assert_eq!(protocol.poll_transmit(), None);
+}
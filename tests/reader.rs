@@ -0,0 +1,168 @@
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::Mutex;
+
+use revsh::frame::Frame;
+use revsh::message::{HeaderOrigin, Message, ProtocolError};
+use revsh::reader::MessageReader;
+
+const CHUNKS: usize = 4096;
+const CHUNK_SIZE: usize = 1024;
+
+/// Source handing out one queued piece per read call, or as much of it as
+/// fits, then EOF. Counts the read calls, each one a syscall on a socket.
+#[derive(Default)]
+struct PieceSource {
+    pieces: VecDeque<Bytes>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl PieceSource {
+    fn new<I: IntoIterator<Item = Bytes>>(pieces: I) -> Self {
+        Self {
+            pieces: pieces.into_iter().collect(),
+            reads: Arc::default(),
+        }
+    }
+}
+
+impl AsyncRead for PieceSource {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if let Some(mut piece) = self.pieces.pop_front() {
+            let len = piece.len().min(buf.remaining());
+            buf.put_slice(&piece.split_to(len));
+            if !piece.is_empty() {
+                self.pieces.push_front(piece);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn encode(frame: Frame) -> Bytes {
+    let mut buf = BytesMut::new();
+    Message::from(frame).encode(&mut buf).unwrap();
+    buf.freeze()
+}
+
+fn data(id: u16, data: &'static [u8]) -> Frame {
+    Frame::ConnectionData {
+        origin: HeaderOrigin::Target,
+        id,
+        data: Bytes::from_static(data),
+    }
+}
+
+async fn read_frame<T: AsyncRead + Unpin>(reader: &mut MessageReader<T>) -> Frame {
+    Frame::try_from(reader.read().await.unwrap()).unwrap()
+}
+
+fn is_eof(result: Result<Message, ProtocolError>) -> bool {
+    matches!(result, Err(ProtocolError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+#[tokio::test]
+async fn frames_split_over_reads() {
+    // Every byte comes in its own read
+    let mut bytes = encode(data(1, b"first")).to_vec();
+    bytes.extend_from_slice(&encode(Frame::Tty(Bytes::from_static(b"id\n"))));
+    let source = PieceSource::new(bytes.into_iter().map(|b| Bytes::from(vec![b])));
+    let mut reader = MessageReader::new(source, 1024);
+
+    assert_eq!(read_frame(&mut reader).await, data(1, b"first"));
+    assert_eq!(
+        read_frame(&mut reader).await,
+        Frame::Tty(Bytes::from_static(b"id\n"))
+    );
+    assert!(is_eof(reader.read().await));
+}
+
+#[tokio::test]
+async fn several_frames_in_one_read() {
+    let mut bytes = BytesMut::new();
+    for frame in [data(1, b"a"), data(2, b"bb"), Frame::Nop] {
+        bytes.extend_from_slice(&encode(frame));
+    }
+    let source = PieceSource::new([bytes.freeze()]);
+    let reads = source.reads.clone();
+    let mut reader = MessageReader::new(source, 1024);
+
+    assert_eq!(read_frame(&mut reader).await, data(1, b"a"));
+    assert_eq!(read_frame(&mut reader).await, data(2, b"bb"));
+    assert_eq!(read_frame(&mut reader).await, Frame::Nop);
+    assert_eq!(reads.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn eof_in_the_middle_of_a_frame() {
+    let frame = encode(data(1, b"cut short"));
+    for len in [1, 4, frame.len() - 1] {
+        let mut bytes = encode(Frame::Nop).to_vec();
+        bytes.extend_from_slice(&frame[..len]);
+        let mut reader = MessageReader::new(PieceSource::new([Bytes::from(bytes)]), 1024);
+
+        assert_eq!(read_frame(&mut reader).await, Frame::Nop);
+        assert!(is_eof(reader.read().await), "{} bytes", len);
+    }
+}
+
+#[tokio::test]
+async fn eof_between_frames() {
+    let mut reader = MessageReader::new(PieceSource::new([encode(Frame::Nop)]), 1024);
+    assert_eq!(read_frame(&mut reader).await, Frame::Nop);
+    assert!(is_eof(reader.read().await));
+}
+
+#[tokio::test]
+async fn frames_are_read_in_bulk() {
+    let frame = encode(Frame::ConnectionData {
+        origin: HeaderOrigin::Target,
+        id: 7,
+        data: Bytes::from(vec![0x41; CHUNK_SIZE]),
+    });
+    // The stream as a socket would hand it out, 64 KiB at a time
+    let mut stream = BytesMut::new();
+    for _ in 0..CHUNKS {
+        stream.extend_from_slice(&frame);
+    }
+    let stream = stream.freeze();
+    let pieces = || {
+        (0..stream.len())
+            .step_by(64 * 1024)
+            .map(|start| stream.slice(start..(start + 64 * 1024).min(stream.len())))
+            .collect::<Vec<_>>()
+    };
+
+    // One read for the header length, the header and the data of each frame
+    let source = PieceSource::new(pieces());
+    let pulled = source.reads.clone();
+    let mut source = Arc::new(Mutex::new(Some(source)));
+    for _ in 0..CHUNKS {
+        Message::pull(&mut source, u16::MAX).await.unwrap();
+    }
+
+    let source = PieceSource::new(pieces());
+    let read = source.reads.clone();
+    let mut reader = MessageReader::new(source, u16::MAX);
+    for _ in 0..CHUNKS {
+        let message = reader.read().await.unwrap();
+        assert_eq!(message.data.len(), CHUNK_SIZE);
+    }
+
+    // Every read picks up many frames instead of a piece of one
+    let pulled = pulled.load(Ordering::Relaxed);
+    let read = read.load(Ordering::Relaxed);
+    assert!(pulled >= 3 * CHUNKS, "{} reads pulling", pulled);
+    assert!(read <= stream.len() / (32 * 1024), "{} reads", read);
+}
@@ -0,0 +1,323 @@
+mod common;
+
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, watch};
+
+use common::{read_until, wait_for_winsize, DuplexAcceptor, MockTarget};
+use revsh::console::Console;
+use revsh::control::Control;
+use revsh::forward::ForwardSpec;
+use revsh::session::{SessionInfo, SessionTable};
+use revsh::transport::{Acceptor, Incoming};
+
+/// Serves sessions into `sessions` for targets queued on the returned
+/// sender.
+fn serve_into(sessions: SessionTable) -> mpsc::UnboundedSender<DuplexStream> {
+    let (streams, receiver) = mpsc::unbounded_channel();
+    let mut control = Control::with_acceptor(DuplexAcceptor(receiver));
+    control.message_data_size(1024);
+    tokio::spawn({
+        let sessions = sessions.clone();
+        async move { control.serve(sessions).await }
+    });
+    streams
+}
+
+fn serve() -> (mpsc::UnboundedSender<DuplexStream>, SessionTable) {
+    let sessions = SessionTable::new();
+    (serve_into(sessions.clone()), sessions)
+}
+
+/// Connects a mock target and returns the task running it.
+async fn connect(streams: &mpsc::UnboundedSender<DuplexStream>) -> tokio::task::JoinHandle<()> {
+    let (control_end, target_end) = tokio::io::duplex(64 * 1024);
+    streams.send(control_end).unwrap();
+    let target = MockTarget::handshake(target_end, 1024).await.unwrap();
+    tokio::spawn(async move {
+        let _ = target.run().await;
+    })
+}
+
+async fn wait_for<F: Fn(&[SessionInfo]) -> bool>(
+    sessions: &SessionTable,
+    f: F,
+) -> Vec<SessionInfo> {
+    for _ in 0..250 {
+        let list = sessions.list().await;
+        if f(&list) {
+            return list;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Sessions never got there: {:?}", sessions.list().await);
+}
+
+async fn recv_until(output: &mut mpsc::UnboundedReceiver<Bytes>, needle: &str) -> String {
+    let mut read = Vec::new();
+    while !String::from_utf8_lossy(&read).contains(needle) {
+        let data = tokio::time::timeout(Duration::from_secs(5), output.recv())
+            .await
+            .expect("timed out")
+            .expect("session closed");
+        read.extend_from_slice(&data);
+    }
+    String::from_utf8(read).unwrap()
+}
+
+#[tokio::test]
+async fn sessions_are_tracked_while_targets_come_and_go() {
+    let (streams, sessions) = serve();
+
+    // A target stuck in the handshake doesn't hold up the next ones
+    let (stuck, _stuck_target) = tokio::io::duplex(1024);
+    streams.send(stuck).unwrap();
+    let first = connect(&streams).await;
+    let _second = connect(&streams).await;
+
+    let list = wait_for(&sessions, |list| list.len() == 2).await;
+    assert_eq!(list.iter().map(|s| s.id).collect::<Vec<_>>(), [1, 2]);
+    assert!(list
+        .iter()
+        .all(|s| s.remote_address == "duplex" && !s.attached));
+
+    let mut io = sessions.attach(2).await.unwrap();
+    assert_eq!(io.id, 2);
+    assert!(sessions.attach(2).await.is_err());
+    assert!(sessions.attach(3).await.is_err());
+    let list = sessions.list().await;
+    assert!(!list[0].attached);
+    assert!(list[1].attached);
+
+    io.input.write_all(b"hostname\n").await.unwrap();
+    assert_eq!(recv_until(&mut io.output, "\n").await, "hostname\n");
+
+    // The first target going away removes its session only
+    first.abort();
+    let list = wait_for(&sessions, |list| list.len() == 1).await;
+    assert_eq!(list[0].id, 2);
+}
+
+/// A listener failing the given ways before handing out streams.
+struct FlakyAcceptor {
+    errors: VecDeque<io::Error>,
+    streams: DuplexAcceptor,
+}
+
+impl Acceptor for FlakyAcceptor {
+    type Stream = DuplexStream;
+
+    async fn accept(&mut self) -> Result<Incoming<DuplexStream>> {
+        match self.errors.pop_front() {
+            Some(e) => Err(e.into()),
+            None => self.streams.accept().await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn serve_retries_failed_accepts_until_they_are_fatal() {
+    let (streams, receiver) = mpsc::unbounded_channel();
+    let mut control = Control::with_acceptor(FlakyAcceptor {
+        errors: VecDeque::from([
+            io::Error::from(io::ErrorKind::ConnectionAborted),
+            io::Error::from_raw_os_error(libc::EMFILE),
+        ]),
+        streams: DuplexAcceptor(receiver),
+    });
+    control.message_data_size(1024);
+    let sessions = SessionTable::new();
+    let server = tokio::spawn({
+        let sessions = sessions.clone();
+        async move { control.serve(sessions).await }
+    });
+
+    let _target = connect(&streams).await;
+    wait_for(&sessions, |list| list.len() == 1).await;
+
+    // Nothing will ever come from the acceptor again
+    drop(streams);
+    let error = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("serve kept going")
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Accept failed: No more streams");
+    assert_eq!(sessions.len().await, 1);
+}
+
+#[tokio::test]
+async fn console_attaches_to_the_picked_session() {
+    let (streams, sessions) = serve();
+    let target = connect(&streams).await;
+    wait_for(&sessions, |list| list.len() == 1).await;
+
+    let (mut terminal_in, input) = tokio::io::duplex(1024);
+    let (output, mut terminal_out) = tokio::io::duplex(64 * 1024);
+    let mut console = Console::new(sessions.clone());
+    console.input(input).output(output);
+    let console = tokio::spawn(console.run());
+
+    let list = read_until(&mut terminal_out, "session> ").await;
+    assert!(list.contains("   1  duplex"), "{}", list);
+
+    terminal_in.write_all(b"7\n").await.unwrap();
+    assert!(read_until(&mut terminal_out, "session> ")
+        .await
+        .starts_with("No session 7"));
+
+    terminal_in.write_all(b"1\n").await.unwrap();
+    terminal_in.write_all(b"echo hi\n").await.unwrap();
+    read_until(&mut terminal_out, "echo hi\n").await;
+    assert!(sessions.list().await[0].attached);
+
+    // Back at the picker once the session ends
+    target.abort();
+    let after = read_until(&mut terminal_out, "session> ").await;
+    assert!(after.contains("Session 1 closed"), "{}", after);
+
+    terminal_in.write_all(b"quit\n").await.unwrap();
+    console.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn detached_output_is_kept_for_replay() {
+    let sessions = SessionTable::new().scrollback_size(8);
+    let streams = serve_into(sessions.clone());
+    let _target = connect(&streams).await;
+    wait_for(&sessions, |list| list.len() == 1).await;
+
+    // The echo arrives after the detach and goes to the scrollback only
+    let mut io = sessions.attach(1).await.unwrap();
+    assert!(io.scrollback.is_empty());
+    io.input.write_all(b"0123456789abcdef\n").await.unwrap();
+    sessions.detach(io).await;
+    assert!(!sessions.list().await[0].attached);
+
+    for _ in 0..250 {
+        let io = sessions.attach(1).await.unwrap();
+        let scrollback = io.scrollback.clone();
+        sessions.detach(io).await;
+        if !scrollback.is_empty() && scrollback.ends_with(b"\n") {
+            assert_eq!(scrollback, "9abcdef\n");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Output never reached the scrollback");
+}
+
+#[tokio::test]
+async fn console_detaches_and_reattaches() {
+    let (streams, sessions) = serve();
+    let _first = connect(&streams).await;
+    let _second = connect(&streams).await;
+    wait_for(&sessions, |list| list.len() == 2).await;
+
+    let (mut terminal_in, input) = tokio::io::duplex(1024);
+    let (output, mut terminal_out) = tokio::io::duplex(64 * 1024);
+    let mut console = Console::new(sessions.clone());
+    console.input(input).output(output);
+    tokio::spawn(console.run());
+    read_until(&mut terminal_out, "session> ").await;
+
+    terminal_in.write_all(b"1\n").await.unwrap();
+    let attached = read_until(&mut terminal_out, "^] detaches\n").await;
+    assert!(attached.contains("Attached to session 1"), "{}", attached);
+    terminal_in.write_all(b"pwd\n").await.unwrap();
+    read_until(&mut terminal_out, "pwd\n").await;
+
+    // Input up to the detach key still goes to the session
+    terminal_in.write_all(b"ls\x1d").await.unwrap();
+    let detached = read_until(&mut terminal_out, "session> ").await;
+    assert!(detached.contains("Detached from session 1"), "{}", detached);
+    assert!(!detached.contains("attached\n"), "{}", detached);
+
+    // Another session meanwhile
+    terminal_in.write_all(b"2\n").await.unwrap();
+    terminal_in.write_all(b"id\n").await.unwrap();
+    read_until(&mut terminal_out, "id\n").await;
+    terminal_in.write_all(b"\x1d").await.unwrap();
+    read_until(&mut terminal_out, "session> ").await;
+
+    // Reattaching replays what session 1 printed so far
+    terminal_in.write_all(b"1\n").await.unwrap();
+    let replay = read_until(&mut terminal_out, "pwd\nls").await;
+    assert!(!replay.contains("id\n"), "{}", replay);
+    terminal_in.write_all(b"whoami\n").await.unwrap();
+    read_until(&mut terminal_out, "whoami\n").await;
+
+    // What follows the detach key goes to the picker and on to the next
+    // session
+    terminal_in.write_all(b"\x1d2\nuname\n").await.unwrap();
+    let switched = read_until(&mut terminal_out, "uname\n").await;
+    assert!(switched.contains("Detached from session 1"), "{}", switched);
+    assert!(switched.contains("Attached to session 2"), "{}", switched);
+}
+
+#[tokio::test]
+async fn console_passes_window_size_to_the_attached_session() {
+    let (streams, sessions) = serve();
+    let (control_end, target_end) = tokio::io::duplex(64 * 1024);
+    streams.send(control_end).unwrap();
+    let target = MockTarget::handshake(target_end, 1024).await.unwrap();
+    let target_winsize = target.winsize.clone();
+    tokio::spawn(target.run());
+    wait_for(&sessions, |list| list.len() == 1).await;
+
+    let (mut terminal_in, input) = tokio::io::duplex(1024);
+    let (output, mut terminal_out) = tokio::io::duplex(64 * 1024);
+    let (winsize, receiver) = watch::channel((30, 100));
+    let mut console = Console::new(sessions.clone());
+    console.input(input).output(output).winsize(receiver);
+    tokio::spawn(console.run());
+    read_until(&mut terminal_out, "session> ").await;
+
+    // A resize at the picker reaches the session once attached
+    winsize.send((40, 120)).unwrap();
+    terminal_in.write_all(b"1\n").await.unwrap();
+    wait_for_winsize(&target_winsize, (40, 120)).await;
+
+    winsize.send((41, 121)).unwrap();
+    wait_for_winsize(&target_winsize, (41, 121)).await;
+}
+
+#[tokio::test]
+async fn startup_forwards_go_to_the_first_session_only() {
+    let sessions = SessionTable::new();
+    let (streams, receiver) = mpsc::unbounded_channel();
+    let mut control = Control::with_acceptor(DuplexAcceptor(receiver));
+    let forward: ForwardSpec = "127.0.0.1:0:127.0.0.1:22".parse().unwrap();
+    control
+        .message_data_size(1024)
+        .remote_forwards(vec![forward.clone()]);
+    tokio::spawn({
+        let sessions = sessions.clone();
+        async move { control.serve(sessions).await }
+    });
+
+    let mut proxies = vec![];
+    for count in 1..=2 {
+        let (control_end, target_end) = tokio::io::duplex(64 * 1024);
+        streams.send(control_end).unwrap();
+        let target = MockTarget::handshake(target_end, 1024).await.unwrap();
+        proxies.push(target.proxies.clone());
+        tokio::spawn(target.run());
+        wait_for(&sessions, |list| list.len() == count).await;
+    }
+
+    for _ in 0..250 {
+        if !proxies[0].lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(*proxies[0].lock().unwrap(), [forward.to_string()]);
+    // The second session had as long to ask for it
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(proxies[1].lock().unwrap().is_empty());
+}
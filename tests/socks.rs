@@ -0,0 +1,189 @@
+use anyhow::Result;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use revsh::socks::{ConnectResult, SocksAddress, SocksRequest, SocksVersion};
+
+/// Feeds `input` to the parser as a client that sends it and hangs up.
+/// Returns the parsed request and what the client was sent back.
+async fn read(input: &[u8]) -> (Result<SocksRequest>, Vec<u8>) {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    client.write_all(input).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    let request = SocksRequest::read(&mut server).await;
+    drop(server);
+    let mut replies = Vec::new();
+    client.read_to_end(&mut replies).await.unwrap();
+    (request, replies)
+}
+
+#[tokio::test]
+async fn socks5_method_negotiation() {
+    // No authentication offered among others
+    let (request, replies) = read(&[5, 3, 2, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 22]).await;
+    assert_eq!(request.unwrap().version, SocksVersion::V5);
+    assert_eq!(replies, [5, 0]);
+
+    // Only username/password and GSSAPI, nothing we support
+    let (request, replies) = read(&[5, 2, 2, 1]).await;
+    assert_eq!(
+        request.unwrap_err().to_string(),
+        "No acceptable socks authentication method"
+    );
+    assert_eq!(replies, [5, 0xff]);
+}
+
+#[tokio::test]
+async fn socks5_ipv4() {
+    let (request, _) = read(&[5, 1, 0, 5, 1, 0, 1, 192, 168, 1, 2, 0x1f, 0x90]).await;
+    let request = request.unwrap();
+    assert_eq!(
+        request.address,
+        SocksAddress::Ipv4(Ipv4Addr::new(192, 168, 1, 2))
+    );
+    assert_eq!(request.port, 8080);
+    assert_eq!(request.connection_string(), "192.168.1.2:8080");
+}
+
+#[tokio::test]
+async fn socks5_domain() {
+    let mut input = vec![5, 1, 0, 5, 1, 0, 3, 11];
+    input.extend(b"example.com");
+    input.extend([0x01, 0xbb]);
+    let (request, _) = read(&input).await;
+    let request = request.unwrap();
+    assert_eq!(
+        request.address,
+        SocksAddress::Domain("example.com".to_string())
+    );
+    // Resolved on the target side
+    assert_eq!(request.connection_string(), "example.com:443");
+}
+
+#[tokio::test]
+async fn socks5_ipv6() {
+    let mut input = vec![5, 1, 0, 5, 1, 0, 4];
+    input.extend(Ipv6Addr::LOCALHOST.octets());
+    input.extend([0, 22]);
+    let (request, _) = read(&input).await;
+    let request = request.unwrap();
+    assert_eq!(request.address, SocksAddress::Ipv6(Ipv6Addr::LOCALHOST));
+    assert_eq!(request.connection_string(), "[::1]:22");
+}
+
+#[tokio::test]
+async fn socks5_unsupported_command_and_address_type() {
+    // BIND
+    let (request, replies) = read(&[5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 1, 0, 22]).await;
+    assert_eq!(request.unwrap_err().to_string(), "Wrong command");
+    assert_eq!(replies, [5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let (request, replies) = read(&[5, 1, 0, 5, 1, 0, 9]).await;
+    assert_eq!(request.unwrap_err().to_string(), "Wrong address type");
+    assert_eq!(replies, [5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+}
+
+#[tokio::test]
+async fn socks5_truncated_input() {
+    let full = [5, 1, 0, 5, 1, 0, 3, 4, b'h', b'o', b's', b't', 0, 22];
+    assert!(read(&full).await.0.is_ok());
+    for len in 0..full.len() {
+        let (request, _) = read(&full[..len]).await;
+        assert!(request.is_err(), "{} bytes", len);
+    }
+}
+
+#[tokio::test]
+async fn socks5_replies() {
+    let (request, _) = read(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 22]).await;
+    let request = request.unwrap();
+    assert_eq!(
+        request.reply(ConnectResult::Granted),
+        [5, 0, 0, 1, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(request.reply(ConnectResult::Refused)[1], 5);
+    assert_eq!(request.reply(ConnectResult::TimedOut)[1], 4);
+}
+
+#[tokio::test]
+async fn socks4_user_id() {
+    let mut input = vec![4, 1, 0, 80, 10, 0, 0, 1];
+    input.extend(b"alice\0");
+    let (request, _) = read(&input).await;
+    let request = request.unwrap();
+    assert_eq!(request.version, SocksVersion::V4);
+    assert_eq!(
+        request.address,
+        SocksAddress::Ipv4(Ipv4Addr::new(10, 0, 0, 1))
+    );
+    assert_eq!(request.connection_string(), "10.0.0.1:80");
+
+    // Nothing after the user id is consumed
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    client.write_all(&input).await.unwrap();
+    client.write_all(b"GET").await.unwrap();
+    SocksRequest::read(&mut server).await.unwrap();
+    let mut rest = [0u8; 3];
+    server.read_exact(&mut rest).await.unwrap();
+    assert_eq!(&rest, b"GET");
+}
+
+#[tokio::test]
+async fn socks4_user_id_length_cap() {
+    let mut input = vec![4, 1, 0, 80, 10, 0, 0, 1];
+    input.extend([b'a'; 255]);
+    input.push(0);
+    assert!(read(&input).await.0.is_ok());
+
+    let mut input = vec![4, 1, 0, 80, 10, 0, 0, 1];
+    input.extend([b'a'; 256]);
+    input.push(0);
+    assert_eq!(
+        read(&input).await.0.unwrap_err().to_string(),
+        "Socks4 field too long"
+    );
+}
+
+#[tokio::test]
+async fn socks4a_hostname() {
+    let mut input = vec![4, 1, 0x01, 0xbb, 0, 0, 0, 1];
+    input.extend(b"bob\0example.com\0");
+    let (request, _) = read(&input).await;
+    let request = request.unwrap();
+    assert_eq!(
+        request.address,
+        SocksAddress::Domain("example.com".to_string())
+    );
+    // Resolved on the target side
+    assert_eq!(request.connection_string(), "example.com:443");
+
+    // 0.0.0.0 is a plain address, not SOCKS4a
+    let (request, _) = read(&[4, 1, 0, 80, 0, 0, 0, 0, 0]).await;
+    assert_eq!(
+        request.unwrap().address,
+        SocksAddress::Ipv4(Ipv4Addr::UNSPECIFIED)
+    );
+
+    let mut input = vec![4, 1, 0, 80, 0, 0, 0, 1, 0];
+    input.extend([b'h'; 256]);
+    input.push(0);
+    assert_eq!(
+        read(&input).await.0.unwrap_err().to_string(),
+        "Socks4 field too long"
+    );
+}
+
+#[tokio::test]
+async fn socks4_replies() {
+    let mut input = vec![4, 1, 0x01, 0xbb, 0, 0, 0, 1, 0];
+    input.extend(b"example.com\0");
+    let (request, _) = read(&input).await;
+    let request = request.unwrap();
+    assert_eq!(
+        request.reply(ConnectResult::Granted),
+        [0, 90, 0x01, 0xbb, 0, 0, 0, 0]
+    );
+    assert_eq!(request.reply(ConnectResult::Refused)[1], 91);
+    assert_eq!(request.reply(ConnectResult::TimedOut)[1], 91);
+}
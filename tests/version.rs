@@ -0,0 +1,54 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use revsh::codec::RevshCodec;
+use revsh::message::{ConnectionHeaderType, DataType, HeaderOrigin, Message};
+use revsh::version::{ProtocolVersion, Quirks};
+
+#[test]
+fn supported_majors_pass() {
+    assert_eq!(
+        ProtocolVersion::new(1, 0).check().unwrap(),
+        ProtocolVersion::CURRENT
+    );
+    // Newer minors of a known major are expected to stay compatible
+    assert!(ProtocolVersion::new(1, 3).check().is_ok());
+}
+
+#[test]
+fn incompatible_majors_fail() {
+    for version in [ProtocolVersion::new(0, 9), ProtocolVersion::new(2, 0)] {
+        let error = version.check().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!("Target speaks protocol {}, control supports 1.x", version)
+        );
+    }
+}
+
+#[test]
+fn version_1_quirks() {
+    assert!(ProtocolVersion::CURRENT.quirks().connection_data_proxy_type);
+}
+
+#[test]
+fn quirks_control_framing() {
+    let message = Message::new()
+        .data_type(DataType::Connection)
+        .header_type(ConnectionHeaderType::Data)
+        .header_origin(HeaderOrigin::Target)
+        .header_id(7)
+        .data(&b"hi"[..]);
+    let without = Quirks {
+        connection_data_proxy_type: false,
+    };
+
+    for (quirks, header_len) in [(Quirks::default(), 11u16), (without, 9)] {
+        let mut codec = RevshCodec::new().quirks(quirks);
+        let mut buf = BytesMut::new();
+        codec.encode(&message, &mut buf).unwrap();
+        assert_eq!(&buf[..2], &header_len.to_be_bytes());
+        assert_eq!(buf.len(), message.encoded_len_with(quirks));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(message.clone()));
+    }
+}
@@ -0,0 +1,292 @@
+use bytes::{Bytes, BytesMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWrite};
+
+use revsh::frame::Frame;
+use revsh::message::{ConnectionHeaderType, DataType, HeaderOrigin, Message, ProtocolError};
+use revsh::writer::MessageWriter;
+
+const CHUNKS: usize = 4096;
+const CHUNK_SIZE: usize = 1024;
+
+/// Sink counting write calls, standing in for the TLS stream where every
+/// write can become its own record.
+#[derive(Clone, Default)]
+struct CountingSink {
+    writes: Arc<AtomicUsize>,
+    bytes: Arc<AtomicUsize>,
+}
+
+impl AsyncWrite for CountingSink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(buf.len(), Ordering::Relaxed);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn chunk_message(id: u16) -> Message {
+    Message::new()
+        .data_type(DataType::Connection)
+        .header_type(ConnectionHeaderType::Data)
+        .header_origin(HeaderOrigin::Control)
+        .header_id(id)
+        .data(vec![0x41; CHUNK_SIZE])
+}
+
+#[tokio::test]
+async fn socks_data_is_coalesced() {
+    // One write per frame, as every frame written on its own
+    let mut single = CountingSink::default();
+    for i in 0..CHUNKS {
+        chunk_message((i % 4) as u16)
+            .write(&mut single)
+            .await
+            .unwrap();
+    }
+
+    // The SOCKS path: proxy readers of four connections feeding the writer task
+    let batched = CountingSink::default();
+    let (writer, writer_task) = MessageWriter::new(batched.clone(), u16::MAX);
+    let writer_task = tokio::spawn(writer_task.run());
+    let mut readers = vec![];
+    for id in 0..4u16 {
+        let writer = writer.clone();
+        readers.push(tokio::spawn(async move {
+            let data = Bytes::from(vec![0x41; CHUNK_SIZE]);
+            for _ in 0..CHUNKS / 4 {
+                writer
+                    .connection_data((HeaderOrigin::Control, id), data.clone())
+                    .await
+                    .unwrap();
+            }
+        }));
+    }
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    drop(writer);
+    writer_task.await.unwrap().unwrap();
+
+    let bytes = single.bytes.load(Ordering::Relaxed);
+    assert_eq!(bytes, batched.bytes.load(Ordering::Relaxed));
+    assert_eq!(single.writes.load(Ordering::Relaxed), CHUNKS);
+
+    // Every write, and so every TLS record, carries many frames instead of
+    // one, which divides the per-record overhead and syscalls
+    let frame_len = chunk_message(0).encoded_len();
+    assert_eq!(bytes / CHUNKS, frame_len);
+    let batched_writes = batched.writes.load(Ordering::Relaxed);
+    assert!(
+        bytes / batched_writes >= 8 * frame_len,
+        "{} bytes per write",
+        bytes / batched_writes
+    );
+}
+
+#[tokio::test]
+async fn payloads_are_split_to_message_data_size() {
+    let (client, mut server) = tokio::io::duplex(64 * 1024);
+    let (writer, writer_task) = MessageWriter::new(client, 1024);
+    let writer_task = tokio::spawn(writer_task.run());
+
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    writer
+        .connection_data((HeaderOrigin::Target, 7), Bytes::from(data.clone()))
+        .await
+        .unwrap();
+    writer
+        .send(Frame::Tty(Bytes::from(vec![0x41; 3000])))
+        .await
+        .unwrap();
+    let oversized_error = writer
+        .send(Frame::Error("x".repeat(2000)))
+        .await
+        .unwrap_err();
+    assert!(oversized_error.downcast_ref::<ProtocolError>().is_some());
+    drop(writer);
+    writer_task.await.unwrap().unwrap();
+
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).await.unwrap();
+    let mut buf = BytesMut::from(&buf[..]);
+    let mut connection_data = Vec::new();
+    let mut tty_len = 0;
+    while let Some(message) = Message::decode(&mut buf, 1024).unwrap() {
+        match Frame::try_from(message).unwrap() {
+            Frame::ConnectionData { origin, id, data } => {
+                assert_eq!((origin, id), (HeaderOrigin::Target, 7));
+                connection_data.extend_from_slice(&data);
+            }
+            Frame::Tty(data) => tty_len += data.len(),
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+    assert!(buf.is_empty());
+    assert_eq!(connection_data, data);
+    assert_eq!(tty_len, 3000);
+}
+
+/// Frames written by a writer task that only starts once `queue` is done,
+/// so everything queued is waiting at once.
+async fn written<F, Fut>(queue: F) -> Vec<Frame>
+where
+    F: FnOnce(MessageWriter) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let (client, mut server) = tokio::io::duplex(64 * 1024);
+    let (writer, writer_task) = MessageWriter::new(client, 1024);
+    queue(writer).await;
+    writer_task.run().await.unwrap();
+
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).await.unwrap();
+    let mut buf = BytesMut::from(&buf[..]);
+    let mut frames = vec![];
+    while let Some(message) = Message::decode(&mut buf, 1024).unwrap() {
+        frames.push(Frame::try_from(message).unwrap());
+    }
+    assert!(buf.is_empty());
+    frames
+}
+
+fn data(id: u16, byte: u8) -> Frame {
+    Frame::ConnectionData {
+        origin: HeaderOrigin::Control,
+        id,
+        data: Bytes::from(vec![byte]),
+    }
+}
+
+#[tokio::test]
+async fn control_frames_go_ahead_of_connection_data() {
+    let key = (HeaderOrigin::Control, 1);
+    let frames = written(|writer| async move {
+        for i in 0..8 {
+            writer
+                .connection_data(key, Bytes::from(vec![i]))
+                .await
+                .unwrap();
+        }
+        writer.connection_destroy(key).await.unwrap();
+        writer
+            .send(Frame::Tty(Bytes::from_static(b"id\n")))
+            .await
+            .unwrap();
+        writer
+            .send(Frame::Winresize { rows: 24, cols: 80 })
+            .await
+            .unwrap();
+        writer
+            .connection_dormant((HeaderOrigin::Target, 2))
+            .await
+            .unwrap();
+        writer
+            .connection_active((HeaderOrigin::Target, 3))
+            .await
+            .unwrap();
+        writer
+            .connection_destroy((HeaderOrigin::Target, 4))
+            .await
+            .unwrap();
+    })
+    .await;
+
+    let mut expected = vec![
+        Frame::Tty(Bytes::from_static(b"id\n")),
+        Frame::Winresize { rows: 24, cols: 80 },
+        Frame::ConnectionDormant {
+            origin: HeaderOrigin::Target,
+            id: 2,
+        },
+        Frame::ConnectionActive {
+            origin: HeaderOrigin::Target,
+            id: 3,
+        },
+        Frame::ConnectionDestroy {
+            origin: HeaderOrigin::Target,
+            id: 4,
+        },
+    ];
+    // A Destroy still comes after the data of its own connection
+    expected.extend((0..8).map(|i| data(1, i)));
+    expected.push(Frame::ConnectionDestroy {
+        origin: HeaderOrigin::Control,
+        id: 1,
+    });
+    assert_eq!(frames, expected);
+}
+
+#[tokio::test]
+async fn connections_take_turns() {
+    let frames = written(|writer| async move {
+        for i in 0..6 {
+            writer
+                .connection_data((HeaderOrigin::Control, 1), Bytes::from(vec![i]))
+                .await
+                .unwrap();
+        }
+        for i in 0..3 {
+            writer
+                .connection_data((HeaderOrigin::Control, 2), Bytes::from(vec![i]))
+                .await
+                .unwrap();
+        }
+    })
+    .await;
+
+    assert_eq!(
+        frames,
+        [
+            data(1, 0),
+            data(2, 0),
+            data(1, 1),
+            data(2, 1),
+            data(1, 2),
+            data(2, 2),
+            data(1, 3),
+            data(1, 4),
+            data(1, 5),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn queued_data_is_written_before_the_task_ends() {
+    let (client, mut server) = tokio::io::duplex(64 * 1024);
+    let (writer, writer_task) = MessageWriter::new(client, 1024);
+    let writer_task = tokio::spawn(writer_task.run());
+    // Let the task go idle, waiting on both channels
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+
+    // Both channels close at once with data still in one of them
+    writer
+        .connection_data((HeaderOrigin::Control, 1), Bytes::from_static(b"last"))
+        .await
+        .unwrap();
+    drop(writer);
+    writer_task.await.unwrap().unwrap();
+
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).await.unwrap();
+    let mut buf = BytesMut::from(&buf[..]);
+    let message = Message::decode(&mut buf, 1024).unwrap().unwrap();
+    assert_eq!(&message.data[..], b"last");
+}